@@ -0,0 +1,71 @@
+/// Cache
+///
+/// A content addressed store for build artifacts that are expensive to
+/// produce, kept between builds. Entries are grouped by namespace and keyed
+/// by a hash of whatever went into producing them, so stale entries are never
+/// read, they just stop being used.
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+pub const DEFAULT_DIR: &str = ".spade-cache";
+
+// Numbers temporary files, so that concurrent writes never share one.
+static TEMPORARY: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Clone, Debug)]
+pub struct Cache {
+    path: PathBuf,
+}
+
+impl Cache {
+    pub fn new(path: PathBuf) -> Self {
+        Cache { path }
+    }
+
+    pub fn get(&self, namespace: &str, key: &str) -> Option<Vec<u8>> {
+        fs::read(self.entry_path(namespace, key)).ok()
+    }
+
+    /// Stores an entry. Failing to write to the cache only costs us the work
+    /// next time around, so errors are reported but otherwise ignored.
+    ///
+    /// Entries are written to a temporary file first and then moved into
+    /// place, so that concurrent readers never see half an entry.
+    pub fn put(&self, namespace: &str, key: &str, bytes: &[u8]) {
+        let path = self.entry_path(namespace, key);
+        let temporary = path.with_extension(format!(
+            "{}-{}.tmp",
+            std::process::id(),
+            TEMPORARY.fetch_add(1, Ordering::Relaxed)
+        ));
+        let result = path
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| fs::write(&temporary, bytes))
+            .and_then(|_| fs::rename(&temporary, &path));
+        if let Err(e) = result {
+            eprintln!("Unable to write cache entry {}: {}", path.display(), e);
+        }
+    }
+
+    fn entry_path(&self, namespace: &str, key: &str) -> PathBuf {
+        self.path.join(namespace).join(key)
+    }
+}
+
+/// Hashes all given parts into a hex encoded cache key.
+pub fn key(parts: &[&[u8]]) -> String {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        // Length prefix each part, so that ("ab", "c") and ("a", "bc") differ.
+        hasher.update((part.len() as u64).to_le_bytes());
+        hasher.update(part);
+    }
+    hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
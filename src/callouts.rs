@@ -0,0 +1,152 @@
+/// Callouts
+///
+/// Obsidian style callouts, i.e. blockquotes starting with a `[!type]`
+/// marker and an optional title:
+///
+/// ```markdown
+/// > [!warning]- Careful now
+/// > Foldable callouts end their marker with `-` (collapsed) or `+` (expanded).
+/// ```
+///
+/// Callouts are rendered as a `div`, or a `details` element when foldable,
+/// with classes themes can style by callout type.
+use crate::content::replace_with_html;
+use comrak::nodes::{AstNode, NodeValue};
+use comrak::{format_html, ComrakOptions};
+use regex::Regex;
+
+lazy_static! {
+    static ref MARKER: Regex = Regex::new(r"^\[!([\w-]+)\]([+-]?)[ \t]*").unwrap();
+}
+
+#[derive(Debug, PartialEq)]
+enum Fold {
+    None,
+    Collapsed,
+    Expanded,
+}
+
+/// Replaces every callout blockquote in the document with its rendered markup.
+pub fn transform<'a>(root: &'a AstNode<'a>, opts: &ComrakOptions) {
+    // Nested callouts are rendered as part of their parent, so we work from
+    // the innermost one outwards.
+    let nodes: Vec<&'a AstNode<'a>> = root.descendants().collect();
+    for node in nodes.into_iter().rev() {
+        if !matches!(node.data.borrow().value, NodeValue::BlockQuote) {
+            continue;
+        }
+        if let Some(html) = render(node, opts) {
+            replace_with_html(node, html);
+        }
+    }
+}
+
+fn render<'a>(blockquote: &'a AstNode<'a>, opts: &ComrakOptions) -> Option<String> {
+    let paragraph = blockquote
+        .first_child()
+        .filter(|p| matches!(p.data.borrow().value, NodeValue::Paragraph))?;
+    let marker_node = paragraph.first_child()?;
+
+    let (kind, fold, rest) = {
+        let data = marker_node.data.borrow();
+        let text = match data.value {
+            NodeValue::Text(ref literal) => String::from_utf8_lossy(literal).to_string(),
+            _ => return None,
+        };
+        let captures = MARKER.captures(&text)?;
+        let fold = match &captures[2] {
+            "-" => Fold::Collapsed,
+            "+" => Fold::Expanded,
+            _ => Fold::None,
+        };
+        let rest = text[captures.get(0)?.end()..].to_string();
+        (captures[1].to_lowercase(), fold, rest)
+    };
+
+    // The title is whatever follows the marker on the first line.
+    marker_node.data.borrow_mut().value = NodeValue::Text(rest.into_bytes());
+    let mut title = String::new();
+    while let Some(inline) = paragraph.first_child() {
+        inline.detach();
+        if let NodeValue::SoftBreak | NodeValue::LineBreak = inline.data.borrow().value {
+            break;
+        }
+        title.push_str(&to_html(inline, opts));
+    }
+    if paragraph.first_child().is_none() {
+        paragraph.detach();
+    }
+
+    let mut title = title.trim().to_string();
+    if title.is_empty() {
+        title = default_title(&kind);
+    }
+
+    let content: String = blockquote
+        .children()
+        .map(|child| to_html(child, opts))
+        .collect();
+    let class = format!("callout callout-{}", kind);
+
+    Some(match fold {
+        Fold::None => format!(
+            "<div class=\"{}\" data-callout=\"{}\">\n<div class=\"callout-title\">{}</div>\n<div class=\"callout-content\">\n{}</div>\n</div>\n",
+            class, kind, title, content
+        ),
+        _ => format!(
+            "<details class=\"{}\" data-callout=\"{}\"{}>\n<summary class=\"callout-title\">{}</summary>\n<div class=\"callout-content\">\n{}</div>\n</details>\n",
+            class,
+            kind,
+            if fold == Fold::Expanded { " open" } else { "" },
+            title,
+            content
+        ),
+    })
+}
+
+fn to_html<'a>(node: &'a AstNode<'a>, opts: &ComrakOptions) -> String {
+    let mut html = vec![];
+    format_html(node, opts, &mut html).unwrap();
+    String::from_utf8_lossy(&html).to_string()
+}
+
+fn default_title(kind: &str) -> String {
+    let mut chars = kind.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::MarkdownOptions;
+    use crate::content::parse_raw;
+
+    #[test]
+    fn transform_renders_foldable_callouts() {
+        let raw = "> [!Warning]- Mind *the* gap\n> Body text\n>\n> More";
+        let content = parse_raw(raw, &MarkdownOptions::default()).unwrap();
+
+        assert_eq!(
+            content.body,
+            "<details class=\"callout callout-warning\" data-callout=\"warning\">\n\
+             <summary class=\"callout-title\">Mind <em>the</em> gap</summary>\n\
+             <div class=\"callout-content\">\n<p>Body text</p>\n<p>More</p>\n</div>\n\
+             </details>\n"
+        );
+    }
+
+    #[test]
+    fn transform_defaults_title_and_leaves_blockquotes_alone() {
+        let raw = "> [!note]\n> Body\n\n> Just a quote";
+        let content = parse_raw(raw, &MarkdownOptions::default()).unwrap();
+
+        assert!(content
+            .body
+            .starts_with("<div class=\"callout callout-note\" data-callout=\"note\">\n<div class=\"callout-title\">Note</div>"));
+        assert!(content
+            .body
+            .ends_with("<blockquote>\n<p>Just a quote</p>\n</blockquote>\n"));
+    }
+}
@@ -0,0 +1,49 @@
+/// Comments
+///
+/// Obsidian comments, `%% like this %%`, are private notes that never make it
+/// into the published site. They may span multiple lines and contain code,
+/// but `%%` within code doesn't open or close a comment.
+use crate::scan::{self, Segment};
+
+/// Removes all comments from raw Markdown. An unclosed comment runs to the
+/// end of the document, like it does in Obsidian.
+pub fn strip(raw: &str) -> String {
+    if !raw.contains("%%") {
+        return raw.to_string();
+    }
+
+    let mut stripped = String::with_capacity(raw.len());
+    let mut in_comment = false;
+    for segment in scan::segments(raw) {
+        let mut text = match segment {
+            Segment::Code(code) => {
+                if !in_comment {
+                    stripped.push_str(code);
+                }
+                continue;
+            }
+            Segment::Prose(text) => text,
+        };
+        while let Some(i) = text.find("%%") {
+            if !in_comment {
+                stripped.push_str(&text[..i]);
+            }
+            in_comment = !in_comment;
+            text = &text[i + 2..];
+        }
+        if !in_comment {
+            stripped.push_str(text);
+        }
+    }
+    stripped
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn strip_removes_inline_and_multiline_comments() {
+        let raw = "Public %%private%% text\n%%\nA `%%` span\n%%\nKeep `%%this%%`";
+
+        assert_eq!(super::strip(raw), "Public  text\n\nKeep `%%this%%`");
+    }
+}
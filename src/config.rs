@@ -0,0 +1,298 @@
+/// Config
+///
+/// Everything a single build needs to know, gathered in one place. Settings
+/// come from command line flags, falling back to a `spade.toml` (or
+/// `spade.yaml`) config file.
+use crate::cache::Cache;
+use crate::content::SortKey;
+use crate::diagrams::DiagramOptions;
+use crate::feeds::FeedOptions;
+use crate::filesystem::Collisions;
+use crate::highlight::HighlightOptions;
+use crate::images::{ImageOptions, Images};
+use crate::sitemap::RobotsOptions;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// The config files we look for in the current directory, in order.
+pub const FILE_NAMES: &[&str] = &["spade.toml", "spade.yaml", "spade.yml"];
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub source: String,
+    pub destination: String,
+    pub theme: String,
+    pub markdown: MarkdownOptions,
+    pub images: ImageOptions,
+    /// The URL the site is published at, e.g. `https://example.com`.
+    pub base_url: Option<String>,
+    pub feeds: FeedOptions,
+    pub robots: RobotsOptions,
+    /// The order of the notes listed on tag pages.
+    pub tag_sort: SortKey,
+    /// The order of the notes listed on section pages.
+    pub section_sort: SortKey,
+    /// The number of notes per page of tag and section pages, or 0 to list
+    /// them all on a single page.
+    pub per_page: usize,
+    pub site: Site,
+    /// Whether to render every page, rather than only those whose inputs
+    /// changed since the previous build.
+    pub full_rebuild: bool,
+    /// What to do about source files ending up at the same permalink.
+    pub collisions: Collisions,
+}
+
+/// Site-wide values, passed to every template as `site`.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Site {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub author: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub base_url: Option<String>,
+    /// Anything else the theme needs, as given in the config file.
+    pub extra: BTreeMap<String, serde_json::Value>,
+}
+
+/// The contents of a config file. Everything is optional, and paths are
+/// relative to the config file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfigFile {
+    pub source: Option<String>,
+    pub destination: Option<String>,
+    pub theme: Option<String>,
+    pub cache: Option<String>,
+    pub base_url: Option<String>,
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub author: Option<String>,
+    pub on_collision: Option<String>,
+    pub markdown: MarkdownFile,
+    pub extra: BTreeMap<String, serde_json::Value>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MarkdownFile {
+    pub allow_html: Option<bool>,
+    pub count_code_blocks: Option<bool>,
+    pub line_numbers: Option<bool>,
+    pub highlight_style: Option<String>,
+    pub highlight_theme: Option<String>,
+    /// Extensions to turn on or off, by name.
+    pub extensions: BTreeMap<String, bool>,
+    /// Diagram renderers, by language.
+    pub diagram_renderers: BTreeMap<String, String>,
+}
+
+impl ConfigFile {
+    /// Reads the config file at `path`, or the first of `FILE_NAMES` in the
+    /// current directory if no path is given. It's only an error for a given
+    /// file to be missing.
+    pub fn load(path: Option<&str>) -> Result<Option<ConfigFile>, crate::error::Error> {
+        let path = match path {
+            Some(path) => PathBuf::from(path),
+            None => match FILE_NAMES.iter().map(PathBuf::from).find(|p| p.is_file()) {
+                Some(path) => path,
+                None => return Ok(None),
+            },
+        };
+        let text = std::fs::read_to_string(&path).map_err(|e| {
+            crate::error::Error::Config(format!("unable to read {}: {}", path.display(), e))
+        })?;
+        let mut file = ConfigFile::parse(&path, &text)?;
+        file.resolve(path.parent().unwrap_or_else(|| Path::new("")));
+        Ok(Some(file))
+    }
+
+    /// Parses a config file, as TOML or YAML depending on its extension.
+    pub fn parse(path: &Path, text: &str) -> Result<ConfigFile, crate::error::Error> {
+        let invalid = |e: &dyn std::fmt::Display| {
+            crate::error::Error::Config(format!("invalid config file {}: {}", path.display(), e))
+        };
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("yaml") | Some("yml") => serde_yaml::from_str(text).map_err(|e| invalid(&e)),
+            _ => toml::from_str(text).map_err(|e| invalid(&e)),
+        }
+    }
+
+    // Makes the paths of the file relative to the directory it's in.
+    fn resolve(&mut self, dir: &Path) {
+        for path in vec![
+            &mut self.source,
+            &mut self.destination,
+            &mut self.theme,
+            &mut self.cache,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            *path = dir.join(&path).to_string_lossy().into_owned();
+        }
+    }
+
+    /// The site-wide values of the file.
+    pub fn site(&self) -> Site {
+        Site {
+            title: self.title.clone(),
+            description: self.description.clone(),
+            author: self.author.clone(),
+            base_url: self.base_url.clone(),
+            extra: self.extra.clone(),
+        }
+    }
+}
+
+/// Options controlling how article Markdown is turned into HTML.
+#[derive(Clone, Debug, Default)]
+pub struct MarkdownOptions {
+    pub allow_html: bool,
+    pub highlight: HighlightOptions,
+    pub extensions: Extensions,
+    pub diagrams: DiagramOptions,
+    /// Whether code blocks count towards word counts and reading times.
+    pub count_code_blocks: bool,
+    /// Where rendered artifacts such as diagrams are kept between builds.
+    pub cache: Option<Cache>,
+    /// The images processed for this build, so that embedded images can point
+    /// at their resized variants.
+    pub images: Images,
+}
+
+/// Markdown syntax extensions, each of which can be turned on or off.
+#[derive(Clone, Debug)]
+pub struct Extensions {
+    pub autolink: bool,
+    pub callouts: bool,
+    pub description_lists: bool,
+    pub footnotes: bool,
+    pub highlights: bool,
+    pub math: bool,
+    pub strikethrough: bool,
+    pub superscript: bool,
+    pub table: bool,
+    pub tasklist: bool,
+}
+
+pub const EXTENSION_NAMES: &[&str] = &[
+    "autolink",
+    "callouts",
+    "description-lists",
+    "footnotes",
+    "highlights",
+    "math",
+    "strikethrough",
+    "superscript",
+    "table",
+    "tasklist",
+];
+
+// Superscript and description lists are off by default, since their syntax
+// is easily triggered by accident (e.g. `2^10`).
+impl Default for Extensions {
+    fn default() -> Self {
+        Extensions {
+            autolink: true,
+            callouts: true,
+            description_lists: false,
+            footnotes: true,
+            highlights: true,
+            math: true,
+            strikethrough: true,
+            superscript: false,
+            table: true,
+            tasklist: true,
+        }
+    }
+}
+
+impl MarkdownOptions {
+    /// Everything besides the Markdown itself that goes into rendering `raw`,
+    /// for cache keys. Only the processed images `raw` embeds count.
+    pub fn fingerprint(&self, raw: &str) -> String {
+        let mut images: Vec<_> = self
+            .images
+            .processed
+            .iter()
+            .filter(|(permalink, _)| raw.contains(permalink.as_str()))
+            .collect();
+        images.sort_by(|a, b| a.0.cmp(b.0));
+        format!(
+            "{:?} {:?} {:?} {:?} {:?} {:?} {:?}",
+            self.allow_html,
+            self.highlight,
+            self.extensions,
+            self.diagrams,
+            self.count_code_blocks,
+            self.images.sizes,
+            images
+        )
+    }
+}
+
+impl Extensions {
+    pub fn set(&mut self, name: &str, enabled: bool) -> Result<(), crate::error::Error> {
+        let extension = match name {
+            "autolink" => &mut self.autolink,
+            "callouts" => &mut self.callouts,
+            "description-lists" => &mut self.description_lists,
+            "footnotes" => &mut self.footnotes,
+            "highlights" => &mut self.highlights,
+            "math" => &mut self.math,
+            "strikethrough" => &mut self.strikethrough,
+            "superscript" => &mut self.superscript,
+            "table" => &mut self.table,
+            "tasklist" => &mut self.tasklist,
+            _ => {
+                return Err(crate::error::Error::Config(format!(
+                    "unknown markdown extension \"{}\"",
+                    name
+                )))
+            }
+        };
+        *extension = enabled;
+        Ok(())
+    }
+}
+
+impl Config {
+    pub fn validate(&self) -> Result<(), crate::error::Error> {
+        self.markdown.highlight.validate()?;
+        self.images.validate()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ConfigFile;
+    use std::path::Path;
+
+    #[test]
+    fn parse_reads_toml_and_yaml() {
+        let toml = "title = \"Notes\"\n\n[markdown]\nallow_html = true\n\n[markdown.extensions]\nmath = false\n\n[extra]\ntwitter = \"@notes\"\n";
+        let yaml = "title: Notes\nmarkdown:\n  allow_html: true\n  extensions:\n    math: false\nextra:\n  twitter: \"@notes\"\n";
+
+        for (name, text) in [("spade.toml", toml), ("spade.yaml", yaml)] {
+            let mut file = ConfigFile::parse(Path::new(name), text).unwrap();
+            assert_eq!(file.title.as_deref(), Some("Notes"));
+            assert_eq!(file.markdown.allow_html, Some(true));
+            assert_eq!(file.markdown.extensions.get("math"), Some(&false));
+            assert_eq!(file.extra["twitter"], "@notes");
+
+            file.source = Some("content".to_string());
+            file.resolve(Path::new("site"));
+            assert_eq!(file.source.as_deref(), Some("site/content"));
+        }
+    }
+
+    #[test]
+    fn parse_rejects_unknown_settings() {
+        assert!(ConfigFile::parse(Path::new("spade.toml"), "titel = \"Notes\"").is_err());
+    }
+}
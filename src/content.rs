@@ -1,9 +1,35 @@
-use crate::frontmatter::Frontmatter;
+use crate::callouts;
+use crate::comments;
+use crate::config::MarkdownOptions;
+use crate::diagrams;
+use crate::footnotes;
+use crate::frontmatter::{self, Frontmatter};
+use crate::highlight::{self, HighlightStyle};
+use crate::images;
 use crate::links;
-use comrak::{format_html, nodes::NodeValue, parse_document, Arena, ComrakOptions};
-use serde::Serialize;
+use crate::marks;
+use crate::math;
+use crate::tags::{self, Tag};
+use crate::tasks::{self, Task};
+use chrono::{DateTime, Utc};
+use comrak::arena_tree::{Node, NodeEdge};
+use comrak::nodes::{Ast, AstNode, NodeHtmlBlock, NodeValue};
+use comrak::{format_html, parse_document, Arena, ComrakOptions};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
 use serde_json::{Map, Value};
-use std::collections::HashMap;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::str::FromStr;
+
+lazy_static! {
+    static ref MARKUP: Regex = Regex::new(r"<[^>]*>").unwrap();
+    // The URLs comrak refuses to render unless rendering unsafely.
+    static ref DANGEROUS_URL: Regex =
+        Regex::new(r"^(?i:javascript|vbscript|file|data):").unwrap();
+    static ref SAFE_DATA_URL: Regex =
+        Regex::new(r"^(?i:data:image/(?:png|gif|jpeg|webp);)").unwrap();
+}
 
 /// Content is any item of data that we want to move or process
 /// from our source to our destination.
@@ -23,16 +49,141 @@ pub struct Article {
     pub raw: String,
 }
 
-#[derive(Clone, Debug, Default, Serialize)]
+impl Article {
+    /// All tags of the article, from its frontmatter as well as inline ones.
+    pub fn tags(&self) -> Vec<String> {
+        let frontmatter = self.meta.iter().flat_map(|m| m.tags.iter().flatten());
+        let inline = self.content.iter().flat_map(|c| c.tags.iter());
+        tags::merge(frontmatter.chain(inline))
+    }
+
+    /// The title from the frontmatter, falling back to the text of the first
+    /// heading and finally the file name.
+    pub fn title(&self) -> String {
+        self.meta
+            .as_ref()
+            .and_then(|m| m.title.clone())
+            .or_else(|| self.content.as_ref().and_then(|c| c.plain_title.clone()))
+            .unwrap_or_else(|| self.id.rsplit('/').next().unwrap_or(&self.id).to_string())
+    }
+
+    pub fn is_draft(&self) -> bool {
+        self.meta.as_ref().and_then(|m| m.draft).unwrap_or(false)
+    }
+
+    pub fn summary(&self) -> ArticleSummary {
+        let meta = self.meta.clone().unwrap_or_default();
+        ArticleSummary {
+            id: self.id.clone(),
+            title: self.title(),
+            permalink: self.permalink.clone(),
+            created_at: meta.created_at,
+            updated_at: meta.updated_at,
+            summary: meta
+                .summary
+                .or_else(|| self.content.as_ref().and_then(|c| c.summary.clone())),
+            tags: self.tags().iter().map(|tag| Tag::new(tag)).collect(),
+            word_count: self.content.as_ref().map_or(0, |c| c.word_count),
+            reading_time: self.content.as_ref().map_or(0, |c| c.reading_time),
+        }
+    }
+}
+
+/// What listings, such as tag pages, get to show of an article.
+#[derive(Clone, Debug, Serialize)]
+pub struct ArticleSummary {
+    pub id: String,
+    pub title: String,
+    pub permalink: String,
+    pub created_at: Option<DateTime<Utc>>,
+    pub updated_at: Option<DateTime<Utc>>,
+    /// The summary from the frontmatter, or the text of the first paragraph.
+    pub summary: Option<String>,
+    pub tags: Vec<Tag>,
+    pub word_count: usize,
+    pub reading_time: usize,
+}
+
+/// The order articles are listed in. Dates sort newest first, with undated
+/// articles last.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SortKey {
+    Title,
+    Created,
+    Updated,
+}
+
+impl FromStr for SortKey {
+    type Err = crate::error::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "title" => Ok(SortKey::Title),
+            "created" => Ok(SortKey::Created),
+            "updated" => Ok(SortKey::Updated),
+            _ => Err(crate::error::Error::Config(format!(
+                "unknown sort key \"{}\", expected title, created or updated",
+                s
+            ))),
+        }
+    }
+}
+
+pub fn sort_summaries(summaries: &mut [ArticleSummary], key: SortKey) {
+    summaries.sort_by(|a, b| {
+        let by_title = a
+            .title
+            .to_lowercase()
+            .cmp(&b.title.to_lowercase())
+            .then_with(|| a.id.cmp(&b.id));
+        match key {
+            SortKey::Title => by_title,
+            // Reversed, so newer dates come first and `None` last.
+            SortKey::Created => b.created_at.cmp(&a.created_at).then(by_title),
+            SortKey::Updated => b
+                .updated_at
+                .or(b.created_at)
+                .cmp(&a.updated_at.or(a.created_at))
+                .then(by_title),
+        }
+    });
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct ArticleContent {
     pub title: Option<String>,
+    /// The title without any markup.
+    pub plain_title: Option<String>,
+    /// The text of the first paragraph, without any markup.
+    pub summary: Option<String>,
     pub ingress: Option<String>,
     pub body: String,
-    pub toc: HashMap<String, String>,
+    pub toc: BTreeMap<String, String>,
+    /// Tags used inline in the body, e.g. `#rust`.
+    pub tags: Vec<String>,
+    pub tasks: Vec<Task>,
+    pub word_count: usize,
+    /// Number of characters, not counting whitespace.
+    pub char_count: usize,
+    /// Estimated reading time in minutes.
+    pub reading_time: usize,
 }
 
+/// Site-wide sums of the article statistics.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Totals {
+    pub articles: usize,
+    pub word_count: usize,
+    pub char_count: usize,
+    pub reading_time: usize,
+}
+
+/// Average reading speed used to estimate reading times.
+const WORDS_PER_MINUTE: usize = 200;
+
 #[derive(Debug)]
 pub struct Asset {
+    #[allow(dead_code)]
     pub id: String,
     pub permalink: String,
     pub src: String,
@@ -51,25 +202,118 @@ pub fn get_article<'a>(contents: &'a HashMap<String, Content>, id: &str) -> Opti
     None
 }
 
-pub fn parse_raw(raw: &str, allow_html: bool) -> Option<ArticleContent> {
+/// Resolves internal links, splits out the frontmatter and renders the
+/// Markdown of an article. Afterwards `raw` holds the body without its
+/// frontmatter or comments, which is what `graph.json` publishes, so that
+/// private comments aren't published anywhere.
+pub fn process(
+    contents: &HashMap<String, Content>,
+    article: &Article,
+    opts: &MarkdownOptions,
+) -> Result<Article, crate::error::Error> {
+    let mut article = links::replace(contents, article)?;
+    let (meta, raw_content) = frontmatter::extract(article.raw);
+    article.meta = meta;
+    article.raw = comments::strip(&raw_content);
+    article.content = parse_cached(&article.raw, opts);
+    Ok(article)
+}
+
+// Parsing is the expensive part of a build, what with syntax highlighting and
+// diagrams, so the results are cached by everything that goes into them.
+fn parse_cached(raw: &str, opts: &MarkdownOptions) -> Option<ArticleContent> {
+    let cache = match &opts.cache {
+        Some(cache) => cache,
+        None => return parse_raw(raw, opts),
+    };
+    let key = crate::cache::key(&[
+        env!("CARGO_PKG_VERSION").as_bytes(),
+        raw.as_bytes(),
+        opts.fingerprint(raw).as_bytes(),
+    ]);
+    if let Some(bytes) = cache.get("articles", &key) {
+        if let Ok(content) = serde_json::from_slice(&bytes) {
+            return content;
+        }
+    }
+    let content = parse_raw(raw, opts);
+    if let Ok(bytes) = serde_json::to_vec(&content) {
+        cache.put("articles", &key, &bytes);
+    }
+    content
+}
+
+pub fn parse_raw(raw: &str, opts: &MarkdownOptions) -> Option<ArticleContent> {
     let mut content = ArticleContent::default();
 
     let mut comrak_opts = ComrakOptions::default();
-    comrak_opts.extension.tasklist = true;
-    comrak_opts.extension.table = true;
-    if allow_html {
-        comrak_opts.render.unsafe_ = true;
-    }
+    comrak_opts.extension.autolink = opts.extensions.autolink;
+    comrak_opts.extension.description_lists = opts.extensions.description_lists;
+    comrak_opts.extension.footnotes = opts.extensions.footnotes;
+    comrak_opts.extension.strikethrough = opts.extensions.strikethrough;
+    comrak_opts.extension.superscript = opts.extensions.superscript;
+    comrak_opts.extension.table = opts.extensions.table;
+    comrak_opts.extension.tasklist = opts.extensions.tasklist;
+    // Raw HTML is always rendered, since that's how our own transforms below
+    // inject markup. HTML and dangerous URLs written by the author are
+    // omitted up front instead, unless explicitly allowed.
+    comrak_opts.render.unsafe_ = true;
+
+    // Comments are private, so they're gone before anything else happens.
+    let raw = comments::strip(raw);
+    let (raw, formulas) = if opts.extensions.math {
+        math::extract(&raw)
+    } else {
+        (raw, vec![])
+    };
 
     let arena = Arena::new();
-    let root = parse_document(&arena, raw, &comrak_opts);
+    let root = parse_document(&arena, &raw, &comrak_opts);
+
+    let (word_count, char_count) = count_text(root, opts.count_code_blocks);
+    content.word_count = word_count;
+    content.char_count = char_count;
+    content.reading_time = word_count.div_ceil(WORDS_PER_MINUTE);
+
+    if !opts.allow_html {
+        omit_unsafe(root);
+    }
+    diagrams::transform(root, &opts.diagrams, opts.cache.as_ref());
+    if opts.highlight.style != HighlightStyle::None {
+        highlight_code_blocks(root, opts);
+    }
+    math::restore(&arena, root, &formulas);
+    images::transform(root, &opts.images);
+    content.tags = tags::transform(&arena, root);
+    if opts.extensions.highlights {
+        marks::transform(&arena, root);
+    }
+    content.tasks = tasks::extract(root);
+    footnotes::rewrite_references(root);
+    if opts.extensions.callouts {
+        callouts::transform(root, &comrak_opts);
+    }
+
+    let mut footnote_definitions = String::new();
 
     for node in root.children() {
+        if content.summary.is_none() && matches!(node.data.borrow().value, NodeValue::Paragraph) {
+            content.summary = Some(plain_text(node)).filter(|text| !text.is_empty());
+        }
         match node.data.clone().into_inner().value {
-            NodeValue::Heading(c) if content.title == None && c.level == 1 => {
+            NodeValue::FootnoteDefinition(name) => {
+                footnote_definitions.push_str(&footnotes::render_definition(
+                    node,
+                    &name,
+                    &comrak_opts,
+                ));
+                continue;
+            }
+            NodeValue::Heading(c) if content.title.is_none() && c.level == 1 => {
                 let mut title = vec![];
                 format_html(node, &comrak_opts, &mut title).unwrap();
                 content.title = Some(String::from_utf8_lossy(&title).to_string());
+                content.plain_title = Some(plain_text(node));
                 continue;
             }
             NodeValue::Heading(c) if c.level == 2 => {
@@ -90,7 +334,7 @@ pub fn parse_raw(raw: &str, allow_html: bool) -> Option<ArticleContent> {
 
                 continue;
             }
-            NodeValue::Paragraph if content.ingress == None => {
+            NodeValue::Paragraph if content.ingress.is_none() => {
                 if let Some(sibling) = node.previous_sibling() {
                     if let NodeValue::Heading(c) = sibling.data.clone().into_inner().value {
                         if c.level == 1 {
@@ -115,9 +359,126 @@ pub fn parse_raw(raw: &str, allow_html: bool) -> Option<ArticleContent> {
         }
     }
 
+    if !footnote_definitions.is_empty() {
+        content
+            .body
+            .push_str(&footnotes::section(&footnote_definitions));
+    }
+
     Some(content)
 }
 
+// Counts the words and non-whitespace characters of the document's text, the
+// way it reads once rendered.
+fn count_text<'a>(root: &'a AstNode<'a>, count_code_blocks: bool) -> (usize, usize) {
+    let mut text = String::new();
+    for edge in root.traverse() {
+        match edge {
+            NodeEdge::Start(node) => match node.data.borrow().value {
+                NodeValue::Text(ref literal) => text.push_str(&String::from_utf8_lossy(literal)),
+                NodeValue::Code(ref code) => text.push_str(&String::from_utf8_lossy(&code.literal)),
+                NodeValue::CodeBlock(ref block) if count_code_blocks => {
+                    text.push_str(&String::from_utf8_lossy(&block.literal))
+                }
+                NodeValue::SoftBreak | NodeValue::LineBreak => text.push(' '),
+                _ => {}
+            },
+            // Words don't run across blocks.
+            NodeEdge::End(node) if node.data.borrow().value.block() => text.push(' '),
+            NodeEdge::End(_) => {}
+        }
+    }
+    (
+        text.split_whitespace().count(),
+        text.chars().filter(|c| !c.is_whitespace()).count(),
+    )
+}
+
+/// The text of a node, the way it reads once rendered, without any markup.
+pub fn plain_text<'a>(node: &'a AstNode<'a>) -> String {
+    let mut text = String::new();
+    for descendant in node.descendants() {
+        match descendant.data.borrow().value {
+            NodeValue::Text(ref literal) => text.push_str(&String::from_utf8_lossy(literal)),
+            NodeValue::Code(ref code) => text.push_str(&String::from_utf8_lossy(&code.literal)),
+            NodeValue::HtmlInline(ref html) => {
+                text.push_str(&MARKUP.replace_all(&String::from_utf8_lossy(html), ""))
+            }
+            NodeValue::SoftBreak | NodeValue::LineBreak => text.push(' '),
+            _ => {}
+        }
+    }
+    text.split_whitespace().collect::<Vec<&str>>().join(" ")
+}
+
+pub fn totals(contents: &HashMap<String, Content>) -> Totals {
+    contents
+        .values()
+        .filter_map(|c| match c {
+            Content::Article(a) => a.content.as_ref(),
+            _ => None,
+        })
+        .fold(Totals::default(), |mut totals, content| {
+            totals.articles += 1;
+            totals.word_count += content.word_count;
+            totals.char_count += content.char_count;
+            totals.reading_time += content.reading_time;
+            totals
+        })
+}
+
+// Mirrors what comrak does for raw HTML and dangerous URLs, such as
+// `javascript:` links, when rendering unsafely is turned off.
+fn omit_unsafe<'a>(root: &'a AstNode<'a>) {
+    for node in root.descendants() {
+        let mut data = node.data.borrow_mut();
+        match data.value {
+            NodeValue::Link(ref mut link) | NodeValue::Image(ref mut link) => {
+                let url = String::from_utf8_lossy(&link.url);
+                if DANGEROUS_URL.is_match(&url) && !SAFE_DATA_URL.is_match(&url) {
+                    link.url = vec![];
+                }
+            }
+            NodeValue::HtmlBlock(ref mut block) => {
+                block.literal = b"<!-- raw HTML omitted -->".to_vec();
+            }
+            NodeValue::HtmlInline(ref mut literal) => {
+                *literal = b"<!-- raw HTML omitted -->".to_vec();
+            }
+            _ => {}
+        }
+    }
+}
+
+fn highlight_code_blocks<'a>(root: &'a AstNode<'a>, opts: &MarkdownOptions) {
+    for node in root.descendants() {
+        let html = match node.data.borrow().value {
+            NodeValue::CodeBlock(ref block) => highlight::render(
+                &String::from_utf8_lossy(&block.literal),
+                &String::from_utf8_lossy(&block.info),
+                &opts.highlight,
+            ),
+            _ => continue,
+        };
+        replace_with_html(node, html);
+    }
+}
+
+/// Allocates a new, detached node in the arena.
+pub fn new_node<'a>(arena: &'a Arena<AstNode<'a>>, value: NodeValue) -> &'a AstNode<'a> {
+    arena.alloc(Node::new(RefCell::new(Ast::new(value))))
+}
+
+/// Swaps a block node for a raw HTML block holding the given markup.
+pub fn replace_with_html<'a>(node: &'a AstNode<'a>, html: String) {
+    while let Some(child) = node.first_child() {
+        child.detach();
+    }
+    let mut block = NodeHtmlBlock::default();
+    block.literal = html.into_bytes();
+    node.data.borrow_mut().value = NodeValue::HtmlBlock(block);
+}
+
 pub fn get_asset<'a>(contents: &'a HashMap<String, Content>, id: &str) -> Option<&'a Asset> {
     if let Some(Content::Asset(asset)) = contents.get(id) {
         return Some(asset);
@@ -133,8 +494,8 @@ pub fn get_asset<'a>(contents: &'a HashMap<String, Content>, id: &str) -> Option
 
 pub fn get_references(contents: &HashMap<String, Content>) -> HashMap<String, Vec<String>> {
     contents
-        .iter()
-        .filter_map(|(_k, c)| match c {
+        .values()
+        .filter_map(|c| match c {
             Content::Article(a) => Some(a),
             _ => None,
         })
@@ -145,29 +506,64 @@ pub fn get_references(contents: &HashMap<String, Content>) -> HashMap<String, Ve
         })
 }
 
+/// The ids of the articles each article links to. Links may leave out leading
+/// folders, as in `[[d]]` for `notes/d`, and links to anything but an article
+/// are left out.
+pub fn resolve_references(
+    contents: &HashMap<String, Content>,
+    references: &HashMap<String, Vec<String>>,
+) -> HashMap<String, Vec<String>> {
+    references
+        .iter()
+        .map(|(source, targets)| {
+            let targets = targets
+                .iter()
+                .filter_map(|target| get_article(contents, target))
+                .map(|article| article.id.clone())
+                .collect();
+            (source.clone(), targets)
+        })
+        .collect()
+}
+
 pub fn json_graph(
     nodes: &HashMap<String, Content>,
     edges: &HashMap<String, Vec<String>>,
 ) -> String {
-    let n: Vec<Value> = nodes
-        .iter()
-        .filter_map(|(_k, c)| match c {
-            Content::Article(a) => Some(a),
+    // Sorted, so that the graph comes out the same every time.
+    let mut articles: Vec<&Article> = nodes
+        .values()
+        .filter_map(|c| match c {
+            Content::Article(a) => Some(a.as_ref()),
             _ => None,
         })
+        .collect();
+    articles.sort_by(|a, b| a.id.cmp(&b.id));
+    let mut sources: Vec<(&String, &Vec<String>)> = edges.iter().collect();
+    sources.sort();
+
+    let n: Vec<Value> = articles
+        .into_iter()
         .map(|a| {
             let mut m = Map::new();
             let mut data = Map::new();
             data.insert("id".to_string(), Value::String(a.id.clone()));
             data.insert("url".to_string(), Value::String(a.permalink.clone()));
             data.insert("content".to_string(), Value::String(a.raw.clone()));
+            if let Some(content) = &a.content {
+                data.insert("word_count".to_string(), Value::from(content.word_count));
+                data.insert(
+                    "reading_time".to_string(),
+                    Value::from(content.reading_time),
+                );
+            }
             m.insert("data".to_string(), Value::Object(data));
             Value::Object(m)
         })
         .collect();
 
-    let e = edges
-        .iter()
+    let e = sources
+        .into_iter()
         .flat_map(|(k, v)| {
             v.iter()
                 .filter(|&id| matches!(nodes.get(id), Some(Content::Article(_))))
@@ -193,3 +589,97 @@ pub fn json_graph(
 
     serde_json::to_string(&object).unwrap()
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::config::MarkdownOptions;
+    use std::collections::HashMap;
+
+    #[test]
+    fn parse_raw_counts_words() {
+        let raw = "# Title\n\nSome **bold**text and `code`.\n\n```\nnot counted\n```\n";
+
+        let content = super::parse_raw(raw, &MarkdownOptions::default()).unwrap();
+        assert_eq!(content.word_count, 5);
+        assert_eq!(content.char_count, 25);
+        assert_eq!(content.reading_time, 1);
+
+        let opts = MarkdownOptions {
+            count_code_blocks: true,
+            ..MarkdownOptions::default()
+        };
+        let content = super::parse_raw(raw, &opts).unwrap();
+        assert_eq!(content.word_count, 7);
+    }
+
+    #[test]
+    fn parse_raw_omits_dangerous_urls() {
+        let raw = "# T\n\n[x](javascript:alert(1)) ![i](javascript:alert(2)) ![d](data:image/png;base64,AA==)";
+        let html = |opts: &MarkdownOptions| {
+            let content = super::parse_raw(raw, opts).unwrap();
+            format!("{}{}", content.ingress.unwrap_or_default(), content.body)
+        };
+
+        let safe = html(&MarkdownOptions::default());
+        assert!(!safe.contains("javascript:"), "{}", safe);
+        assert!(safe.contains("<a href=\"\">x</a>"), "{}", safe);
+        assert!(
+            safe.contains("src=\"data:image/png;base64,AA==\""),
+            "{}",
+            safe
+        );
+
+        let opts = MarkdownOptions {
+            allow_html: true,
+            ..MarkdownOptions::default()
+        };
+        assert!(html(&opts).contains("href=\"javascript:alert(1)\""));
+    }
+
+    #[test]
+    fn json_graph_leaves_out_frontmatter_and_comments() {
+        let article = super::Article {
+            id: "a".to_string(),
+            permalink: "/a".to_string(),
+            src: "a.md".to_string(),
+            meta: None,
+            content: None,
+            raw: "---\ntitle: Secret title\n---\nBody %% secret plan %%".to_string(),
+        };
+        let mut contents = HashMap::new();
+        contents.insert("a".to_string(), super::Content::Article(Box::new(article)));
+        let article = super::get_article(&contents, "a").unwrap();
+        let article = super::process(&contents, article, &MarkdownOptions::default()).unwrap();
+        contents.insert("a".to_string(), super::Content::Article(Box::new(article)));
+
+        let graph = super::json_graph(&contents, &HashMap::new());
+        assert!(graph.contains("Body"), "{}", graph);
+        assert!(!graph.contains("Secret title"), "{}", graph);
+        assert!(!graph.contains("secret plan"), "{}", graph);
+    }
+
+    #[test]
+    fn sort_summaries_puts_newest_first() {
+        let summary = |title: &str, created_at: Option<&str>| super::ArticleSummary {
+            id: title.to_string(),
+            title: title.to_string(),
+            permalink: format!("/{}", title),
+            created_at: created_at.map(|d| d.parse().unwrap()),
+            updated_at: None,
+            summary: None,
+            tags: vec![],
+            word_count: 0,
+            reading_time: 0,
+        };
+        let mut summaries = vec![
+            summary("b", None),
+            summary("a", None),
+            summary("old", Some("2020-01-01T00:00:00Z")),
+            summary("new", Some("2021-01-01T00:00:00Z")),
+        ];
+
+        super::sort_summaries(&mut summaries, super::SortKey::Created);
+        let titles: Vec<&str> = summaries.iter().map(|s| s.title.as_str()).collect();
+        assert_eq!(titles, vec!["new", "old", "a", "b"]);
+    }
+}
@@ -0,0 +1,168 @@
+/// Diagrams
+///
+/// Fenced code blocks in a diagram language (e.g. ```` ```dot ````) are
+/// handed to a configured local command at build time, which gets the
+/// diagram source on stdin and is expected to write SVG to stdout. The SVG is
+/// inlined in the article and cached by content hash.
+///
+/// Commands are split on whitespace, without any shell quoting, so arguments
+/// can't contain spaces. Anything more involved belongs in a script.
+///
+/// Without a configured renderer, or when the renderer fails, the source is
+/// left in a `<pre class="diagram {lang}">` block for client side libraries
+/// such as mermaid.js to pick up.
+use crate::cache::{self, Cache};
+use crate::content::replace_with_html;
+use comrak::nodes::{AstNode, NodeValue};
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+pub const DIAGRAM_LANGUAGES: &[&str] = &["dot", "mermaid", "plantuml"];
+
+const CACHE_NAMESPACE: &str = "diagrams";
+
+#[derive(Clone, Debug, Default)]
+pub struct DiagramOptions {
+    /// Maps a diagram language to the command rendering it, e.g. `dot -Tsvg`.
+    pub renderers: BTreeMap<String, String>,
+}
+
+/// Replaces every diagram code block in the document with its rendered markup.
+pub fn transform<'a>(root: &'a AstNode<'a>, opts: &DiagramOptions, cache: Option<&Cache>) {
+    for node in root.descendants() {
+        let html = match node.data.borrow().value {
+            NodeValue::CodeBlock(ref block) => {
+                let info = String::from_utf8_lossy(&block.info);
+                let lang = info.split_whitespace().next().unwrap_or("");
+                if !DIAGRAM_LANGUAGES.contains(&lang) && !opts.renderers.contains_key(lang) {
+                    continue;
+                }
+                render(lang, &String::from_utf8_lossy(&block.literal), opts, cache)
+            }
+            _ => continue,
+        };
+        replace_with_html(node, html);
+    }
+}
+
+fn render(lang: &str, source: &str, opts: &DiagramOptions, cache: Option<&Cache>) -> String {
+    let fallback = || {
+        format!(
+            "<pre class=\"diagram {}\">{}</pre>\n",
+            tera::escape_html(lang),
+            tera::escape_html(source)
+        )
+    };
+    let command = match opts.renderers.get(lang) {
+        Some(command) => command,
+        None => return fallback(),
+    };
+
+    let key = cache::key(&[lang.as_bytes(), command.as_bytes(), source.as_bytes()]);
+    let svg = match cache.and_then(|c| c.get(CACHE_NAMESPACE, &key)) {
+        Some(svg) => String::from_utf8_lossy(&svg).to_string(),
+        None => match run(command, source) {
+            Ok(svg) => {
+                if let Some(c) = cache {
+                    c.put(CACHE_NAMESPACE, &key, svg.as_bytes());
+                }
+                svg
+            }
+            Err(e) => {
+                eprintln!(
+                    "Unable to render {} diagram with `{}`: {}",
+                    lang, command, e
+                );
+                return fallback();
+            }
+        },
+    };
+
+    format!(
+        "<figure class=\"diagram diagram-{}\">\n{}\n</figure>\n",
+        tera::escape_html(lang),
+        svg.trim()
+    )
+}
+
+fn run(command: &str, source: &str) -> Result<String, std::io::Error> {
+    let mut args = command.split_whitespace();
+    let program = args.next().ok_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, "empty renderer command")
+    })?;
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    // The source is written from a thread of its own, so that a renderer
+    // writing output before it's done reading doesn't block on a full pipe.
+    let stdin = child.stdin.take();
+    let source = source.to_string();
+    let writer = std::thread::spawn(move || match stdin {
+        Some(mut stdin) => stdin.write_all(source.as_bytes()),
+        None => Ok(()),
+    });
+    let output = child.wait_with_output()?;
+    let written = writer
+        .join()
+        .unwrap_or_else(|_| Err(std::io::Error::other("unable to write to renderer")));
+    if !output.status.success() {
+        return Err(std::io::Error::other(format!(
+            "exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    written?;
+
+    let svg = String::from_utf8_lossy(&output.stdout);
+    // Inline SVG can't carry an XML declaration or doctype.
+    match svg.find("<svg") {
+        Some(start) => Ok(svg[start..].to_string()),
+        None => Err(std::io::Error::other("renderer produced no SVG")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_falls_back_without_renderer() {
+        let html = render(
+            "mermaid",
+            "graph TD; A-->B",
+            &DiagramOptions::default(),
+            None,
+        );
+
+        assert_eq!(
+            html,
+            "<pre class=\"diagram mermaid\">graph TD; A--&gt;B</pre>\n"
+        );
+    }
+
+    #[test]
+    fn render_inlines_renderer_output() {
+        let mut opts = DiagramOptions::default();
+        opts.renderers.insert("dot".to_string(), "cat".to_string());
+        let html = render("dot", "<?xml version=\"1.0\"?>\n<svg></svg>\n", &opts, None);
+
+        assert_eq!(
+            html,
+            "<figure class=\"diagram diagram-dot\">\n<svg></svg>\n</figure>\n"
+        );
+    }
+
+    #[test]
+    fn run_handles_output_larger_than_a_pipe() {
+        // `cat` writes as it reads, and fills the stdout pipe long before
+        // it's done reading.
+        let source = format!("<svg>{}</svg>", "x".repeat(1 << 20));
+        assert_eq!(run("cat", &source).unwrap(), source);
+    }
+}
@@ -8,11 +8,53 @@ pub enum Error {
     Yaml(serde_yaml::Error),
     Template(tera::Error),
     Watch(notify::Error),
+    Image(image::ImageError),
+    Config(String),
+    Output(String),
+    /// Everything that went wrong in a step of the build done in parallel.
+    Multiple(Vec<Error>),
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "Error!")
+        match self {
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+            Error::Utf8(e) => write!(f, "UTF-8 error: {}", e),
+            Error::FromUtf8(e) => write!(f, "UTF-8 error: {}", e),
+            Error::Yaml(e) => write!(f, "YAML error: {}", e),
+            Error::Template(e) => write!(f, "Template error: {}", e),
+            Error::Watch(e) => write!(f, "Watch error: {}", e),
+            Error::Image(e) => write!(f, "Image error: {}", e),
+            Error::Config(e) => write!(f, "Configuration error: {}", e),
+            Error::Output(e) => write!(f, "Output error: {}", e),
+            Error::Multiple(errors) => {
+                write!(f, "{} errors:", errors.len())?;
+                for e in errors {
+                    write!(f, "\n  {}", e)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Collects the results of a parallel step, failing with every error rather
+/// than just the first. Errors are sorted, so that they're reported the same
+/// way every time.
+pub fn collect<T>(results: Vec<Result<T, Error>>) -> Result<Vec<T>, Error> {
+    let mut oks = vec![];
+    let mut errors = vec![];
+    for result in results {
+        match result {
+            Ok(ok) => oks.push(ok),
+            Err(e) => errors.push(e),
+        }
+    }
+    errors.sort_by_key(|e| e.to_string());
+    match errors.len() {
+        0 => Ok(oks),
+        1 => Err(errors.remove(0)),
+        _ => Err(Error::Multiple(errors)),
     }
 }
 
@@ -51,3 +93,35 @@ impl std::convert::From<notify::Error> for Error {
         Error::Watch(error)
     }
 }
+
+impl std::convert::From<image::ImageError> for Error {
+    fn from(error: image::ImageError) -> Self {
+        Error::Image(error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Error;
+
+    #[test]
+    fn collect_reports_every_error() {
+        let results = vec![
+            Ok(1),
+            Err(Error::Config("b".to_string())),
+            Err(Error::Config("a".to_string())),
+        ];
+
+        match super::collect(results) {
+            Err(Error::Multiple(errors)) => assert_eq!(
+                errors.iter().map(|e| e.to_string()).collect::<Vec<_>>(),
+                vec!["Configuration error: a", "Configuration error: b"]
+            ),
+            other => panic!("expected multiple errors, got {:?}", other),
+        }
+        assert_eq!(
+            super::collect::<u8>(vec![Ok(1), Ok(2)]).unwrap(),
+            vec![1, 2]
+        );
+    }
+}
@@ -0,0 +1,355 @@
+/// Feeds
+///
+/// Atom (and optionally RSS 2.0) feeds of the most recently updated
+/// articles, for the whole site as well as for every tag. Feeds need
+/// absolute URLs, so they're only generated when a base URL is configured.
+/// Articles without a `created_at` or `updated_at` date are left out, since
+/// there's no telling where they'd go.
+///
+/// There's also a JSON Feed of every article, which is meant for tools
+/// rather than feed readers, so it's always generated.
+use crate::content::{sort_summaries, Article, SortKey};
+use chrono::{DateTime, Utc};
+use regex::{Captures, Regex};
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+
+lazy_static! {
+    // Site relative, but not protocol relative (`//example.com`), URLs.
+    static ref RELATIVE: Regex = Regex::new(r#"(\s(?:href|src))="/([^/])"#).unwrap();
+    static ref SRCSET: Regex = Regex::new(r#"srcset="([^"]*)""#).unwrap();
+}
+
+#[derive(Clone, Debug)]
+pub struct FeedOptions {
+    pub title: String,
+    /// The maximum number of articles in a feed.
+    pub limit: usize,
+    /// Whether entries hold the full article, rather than its summary.
+    pub full_content: bool,
+    pub rss: bool,
+}
+
+impl Default for FeedOptions {
+    fn default() -> Self {
+        FeedOptions {
+            title: "Spade".to_string(),
+            limit: 20,
+            full_content: true,
+            rss: false,
+        }
+    }
+}
+
+/// A feed, at `{path}/atom.xml` and `{path}/rss.xml`.
+pub struct Feed<'a> {
+    pub title: String,
+    /// The URL of the page the feed belongs to, e.g. `/tags/rust`.
+    pub path: String,
+    pub articles: Vec<&'a Article>,
+}
+
+fn date(article: &Article) -> Option<DateTime<Utc>> {
+    let meta = article.meta.as_ref()?;
+    meta.updated_at.or(meta.created_at)
+}
+
+impl<'a> Feed<'a> {
+    /// Picks the most recent of the given articles for a feed.
+    pub fn new<I: IntoIterator<Item = &'a Article>>(
+        title: String,
+        path: &str,
+        articles: I,
+        opts: &FeedOptions,
+    ) -> Self {
+        let mut articles: Vec<&Article> =
+            articles.into_iter().filter(|a| date(a).is_some()).collect();
+        articles.sort_by(|a, b| date(b).cmp(&date(a)).then(a.id.cmp(&b.id)));
+        articles.truncate(opts.limit);
+        Feed {
+            title,
+            path: path.trim_end_matches('/').to_string(),
+            articles,
+        }
+    }
+
+    pub fn atom_path(&self) -> String {
+        format!("{}/atom.xml", self.path)
+    }
+
+    pub fn rss_path(&self) -> String {
+        format!("{}/rss.xml", self.path)
+    }
+
+    // The absolute URL of the page the feed belongs to.
+    fn page_url(&self, base_url: &str) -> String {
+        match self.path.as_str() {
+            "" => format!("{}/", base_url),
+            path => format!("{}{}", base_url, path),
+        }
+    }
+
+    fn updated(&self) -> DateTime<Utc> {
+        self.articles
+            .iter()
+            .filter_map(|a| date(a))
+            .max()
+            .unwrap_or_else(Utc::now)
+    }
+
+    pub fn atom(&self, base_url: &str, opts: &FeedOptions) -> String {
+        let base_url = base_url.trim_end_matches('/');
+        let page = self.page_url(base_url);
+        let mut xml = format!(
+            "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<feed xmlns=\"http://www.w3.org/2005/Atom\">\n  <title>{}</title>\n  <link href=\"{}{}\" rel=\"self\" type=\"application/atom+xml\"/>\n  <link href=\"{}\"/>\n  <id>{}</id>\n  <updated>{}</updated>\n  <generator>Spade</generator>\n",
+            escape(&self.title),
+            base_url,
+            self.atom_path(),
+            page,
+            page,
+            self.updated().to_rfc3339()
+        );
+        for article in &self.articles {
+            let summary = article.summary();
+            let url = format!("{}{}", base_url, article.permalink);
+            xml.push_str(&format!(
+                "  <entry>\n    <title>{}</title>\n    <link href=\"{}\"/>\n    <id>{}</id>\n    <updated>{}</updated>\n",
+                escape(&summary.title),
+                url,
+                url,
+                date(article).unwrap_or_else(Utc::now).to_rfc3339()
+            ));
+            if let Some(created_at) = summary.created_at {
+                xml.push_str(&format!(
+                    "    <published>{}</published>\n",
+                    created_at.to_rfc3339()
+                ));
+            }
+            for tag in &summary.tags {
+                xml.push_str(&format!("    <category term=\"{}\"/>\n", escape(&tag.name)));
+            }
+            match (opts.full_content, html(article, base_url)) {
+                (true, Some(html)) => xml.push_str(&format!(
+                    "    <content type=\"html\">{}</content>\n",
+                    escape(&html)
+                )),
+                _ => {
+                    if let Some(text) = summary.summary {
+                        xml.push_str(&format!("    <summary>{}</summary>\n", escape(&text)));
+                    }
+                }
+            }
+            xml.push_str("  </entry>\n");
+        }
+        xml.push_str("</feed>\n");
+        xml
+    }
+
+    pub fn rss(&self, base_url: &str, opts: &FeedOptions) -> String {
+        let base_url = base_url.trim_end_matches('/');
+        let mut xml = format!(
+            "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<rss version=\"2.0\" xmlns:atom=\"http://www.w3.org/2005/Atom\">\n<channel>\n  <title>{}</title>\n  <link>{}</link>\n  <description>{}</description>\n  <atom:link href=\"{}{}\" rel=\"self\" type=\"application/rss+xml\"/>\n  <lastBuildDate>{}</lastBuildDate>\n  <generator>Spade</generator>\n",
+            escape(&self.title),
+            self.page_url(base_url),
+            escape(&self.title),
+            base_url,
+            self.rss_path(),
+            self.updated().to_rfc2822()
+        );
+        for article in &self.articles {
+            let summary = article.summary();
+            let url = format!("{}{}", base_url, article.permalink);
+            xml.push_str(&format!(
+                "  <item>\n    <title>{}</title>\n    <link>{}</link>\n    <guid isPermaLink=\"true\">{}</guid>\n    <pubDate>{}</pubDate>\n",
+                escape(&summary.title),
+                url,
+                url,
+                summary
+                    .created_at
+                    .or_else(|| date(article))
+                    .unwrap_or_else(Utc::now)
+                    .to_rfc2822()
+            ));
+            for tag in &summary.tags {
+                xml.push_str(&format!("    <category>{}</category>\n", escape(&tag.name)));
+            }
+            let description = match opts.full_content {
+                true => html(article, base_url),
+                false => None,
+            }
+            .or(summary.summary);
+            if let Some(description) = description {
+                xml.push_str(&format!(
+                    "    <description>{}</description>\n",
+                    escape(&description)
+                ));
+            }
+            xml.push_str("  </item>\n");
+        }
+        xml.push_str("</channel>\n</rss>\n");
+        xml
+    }
+}
+
+/// A JSON Feed 1.1 document of all articles, newest first. URLs are absolute
+/// when there's a base URL, and relative to the site otherwise.
+pub fn json_feed<'a, I: IntoIterator<Item = &'a Article>>(
+    articles: I,
+    base_url: Option<&str>,
+    opts: &FeedOptions,
+) -> String {
+    let base_url = base_url.unwrap_or("").trim_end_matches('/');
+    let articles: HashMap<&str, &Article> =
+        articles.into_iter().map(|a| (a.id.as_str(), a)).collect();
+    let mut summaries: Vec<_> = articles.values().map(|a| a.summary()).collect();
+    sort_summaries(&mut summaries, SortKey::Updated);
+
+    let items: Vec<Value> = summaries
+        .into_iter()
+        .map(|summary| {
+            let article = articles.get(summary.id.as_str());
+            let url = format!("{}{}", base_url, summary.permalink);
+            let mut item = Map::new();
+            item.insert("id".to_string(), json!(url));
+            item.insert("url".to_string(), json!(url));
+            item.insert("title".to_string(), json!(summary.title));
+            if let Some(text) = &summary.summary {
+                item.insert("summary".to_string(), json!(text));
+            }
+            if let Some(html) = article.and_then(|a| html(a, base_url)) {
+                item.insert("content_html".to_string(), json!(html));
+            }
+            if let Some(created_at) = summary.created_at {
+                item.insert("date_published".to_string(), json!(created_at.to_rfc3339()));
+            }
+            if let Some(updated_at) = summary.updated_at {
+                item.insert("date_modified".to_string(), json!(updated_at.to_rfc3339()));
+            }
+            let tags: Vec<&str> = summary.tags.iter().map(|t| t.name.as_str()).collect();
+            if !tags.is_empty() {
+                item.insert("tags".to_string(), json!(tags));
+            }
+            Value::Object(item)
+        })
+        .collect();
+
+    let mut feed = Map::new();
+    feed.insert(
+        "version".to_string(),
+        json!("https://jsonfeed.org/version/1.1"),
+    );
+    feed.insert("title".to_string(), json!(opts.title));
+    if !base_url.is_empty() {
+        feed.insert("home_page_url".to_string(), json!(format!("{}/", base_url)));
+        feed.insert(
+            "feed_url".to_string(),
+            json!(format!("{}/assets/feed.json", base_url)),
+        );
+    }
+    feed.insert("items".to_string(), Value::Array(items));
+    serde_json::to_string(&Value::Object(feed)).unwrap()
+}
+
+// The rendered article, with site relative links made absolute so that they
+// work in feed readers.
+fn html(article: &Article, base_url: &str) -> Option<String> {
+    let content = article.content.as_ref()?;
+    // The title is already part of the entry.
+    let html = format!(
+        "{}{}",
+        content.ingress.as_deref().unwrap_or(""),
+        content.body
+    );
+    let html = RELATIVE.replace_all(&html, |captures: &Captures| {
+        format!("{}=\"{}/{}", &captures[1], base_url, &captures[2])
+    });
+    let html = SRCSET.replace_all(&html, |captures: &Captures| {
+        let sources: Vec<String> = captures[1]
+            .split(", ")
+            .map(
+                |source| match source.starts_with('/') && !source.starts_with("//") {
+                    true => format!("{}{}", base_url, source),
+                    false => source.to_string(),
+                },
+            )
+            .collect();
+        format!("srcset=\"{}\"", sources.join(", "))
+    });
+    Some(html.to_string())
+}
+
+pub fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Feed, FeedOptions};
+    use crate::content::{Article, ArticleContent};
+    use crate::frontmatter::Frontmatter;
+
+    fn article(id: &str, updated_at: Option<&str>) -> Article {
+        Article {
+            id: id.to_string(),
+            permalink: format!("/{}", id),
+            src: format!("{}.md", id),
+            meta: Some(Frontmatter {
+                updated_at: updated_at.map(|d| d.parse().unwrap()),
+                ..Frontmatter::default()
+            }),
+            content: Some(ArticleContent {
+                body: "<p><a href=\"/other\">Other</a> & more</p>\n".to_string(),
+                ..ArticleContent::default()
+            }),
+            raw: String::new(),
+        }
+    }
+
+    #[test]
+    fn json_feed_lists_all_articles() {
+        let articles = vec![
+            article("undated", None),
+            article("new", Some("2021-01-01T00:00:00Z")),
+        ];
+        let feed: serde_json::Value =
+            serde_json::from_str(&super::json_feed(&articles, None, &FeedOptions::default()))
+                .unwrap();
+
+        assert_eq!(feed["version"], "https://jsonfeed.org/version/1.1");
+        assert_eq!(feed["items"][0]["url"], "/new");
+        assert_eq!(
+            feed["items"][0]["date_modified"],
+            "2021-01-01T00:00:00+00:00"
+        );
+        assert_eq!(feed["items"][1]["id"], "/undated");
+        assert_eq!(
+            feed["items"][1]["content_html"],
+            "<p><a href=\"/other\">Other</a> & more</p>\n"
+        );
+    }
+
+    #[test]
+    fn atom_lists_recent_dated_articles() {
+        let articles = vec![
+            article("old", Some("2020-01-01T00:00:00Z")),
+            article("undated", None),
+            article("new", Some("2021-01-01T00:00:00Z")),
+        ];
+        let opts = FeedOptions::default();
+        let feed = Feed::new("Garden".to_string(), "", &articles, &opts);
+        let xml = feed.atom("https://example.com/", &opts);
+
+        assert_eq!(feed.atom_path(), "/atom.xml");
+        assert!(xml.contains("<link href=\"https://example.com/atom.xml\" rel=\"self\""));
+        assert!(xml.contains("<updated>2021-01-01T00:00:00+00:00</updated>"));
+        assert!(!xml.contains("undated"));
+        assert!(xml.find("/new").unwrap() < xml.find("/old").unwrap());
+        assert!(xml.contains(
+            "&lt;a href=&quot;https://example.com/other&quot;&gt;Other&lt;/a&gt; &amp; more"
+        ));
+    }
+}
@@ -2,74 +2,142 @@ use crate::content::{Article, Asset, Content};
 use crate::error::Error;
 use std::collections::HashMap;
 use std::io::{Read, Write};
+use std::str::FromStr;
 use std::{fs, path};
 
+/// What to do about source files that end up at the same permalink, like
+/// `My Note.md` and `my-note.md`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Collisions {
+    /// Fail the build.
+    Error,
+    /// Add a numeric suffix to all but the first, e.g. `/my-note-2`.
+    Suffix,
+}
+
+impl FromStr for Collisions {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "error" => Ok(Collisions::Error),
+            "suffix" => Ok(Collisions::Suffix),
+            _ => Err(Error::Config(format!(
+                "unknown collision policy \"{}\", expected error or suffix",
+                s
+            ))),
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct Filesystem {
     path: path::PathBuf,
+    collisions: Collisions,
+    /// Files written by something else, by output file, e.g. `404.html`.
+    reserved: HashMap<String, String>,
 }
 
 impl Filesystem {
     pub fn new(path: path::PathBuf) -> Self {
-        Filesystem { path }
+        Filesystem {
+            path,
+            collisions: Collisions::Error,
+            reserved: HashMap::new(),
+        }
+    }
+
+    /// Sets the files `read_all` reports source files colliding with, along
+    /// with what writes them, e.g. `the theme's main.js`.
+    pub fn reserve(self, reserved: HashMap<String, String>) -> Self {
+        Filesystem { reserved, ..self }
+    }
+
+    /// Sets what `read_all` does about files ending up at the same permalink.
+    pub fn on_collision(self, collisions: Collisions) -> Self {
+        Filesystem { collisions, ..self }
+    }
+
+    /// Whether there's a file at `permalink`.
+    pub fn exists(&self, permalink: &str) -> bool {
+        self.path.join(permalink.trim_start_matches('/')).is_file()
+    }
+
+    /// The size and modification time of the file at `src`, which tell
+    /// whether it changed between builds without reading it.
+    pub fn stamp(&self, src: &str) -> Option<String> {
+        let metadata = fs::metadata(self.path.join(src)).ok()?;
+        let modified = metadata
+            .modified()
+            .ok()?
+            .duration_since(std::time::UNIX_EPOCH)
+            .ok()?;
+        Some(format!("{}-{}", metadata.len(), modified.as_nanos()))
     }
 }
 
 impl crate::traits::Reader for Filesystem {
     fn read_all(&self) -> Result<HashMap<String, Content>, crate::error::Error> {
         let mut hm = HashMap::new();
-        recursive_read(path::PathBuf::default(), &self.path)?
-            .into_iter()
-            .for_each(|(path, mut file)| {
-                if Filetype::from(&path) == Filetype::Markdown {
-                    let mut buf: Vec<u8> = vec![];
-                    file.read_to_end(&mut buf).unwrap(); //TODO
-                    let id = id_from_path(&path);
-                    hm.insert(
-                        id.clone(),
-                        Content::Article(Box::new(Article {
-                            id,
-                            permalink: permalink_from_path(&path),
-                            src: path.to_str().unwrap().to_string(),
-                            meta: None,
-                            raw: String::from_utf8_lossy(&buf).into(),
-                            content: None,
-                        })),
-                    );
-                } else {
-                    let id = id_from_path(&path);
-                    hm.insert(
-                        id.clone(),
-                        Content::Asset(Asset {
-                            id,
-                            permalink: permalink_from_path(&path),
-                            src: path.into_os_string().into_string().unwrap(),
-                        }),
-                    );
-                }
-            });
+        let mut files: HashMap<String, fs::File> =
+            recursive_read(path::PathBuf::default(), &self.path)?
+                .into_iter()
+                .map(|(path, file)| (path.to_str().unwrap().to_string(), file))
+                .collect();
+        let targets = files.keys().map(|src| Target::new(src)).collect();
+        for target in resolve(targets, &self.reserved, self.collisions)? {
+            let mut file = files.remove(&target.src).unwrap();
+            if target.markdown {
+                let mut buf: Vec<u8> = vec![];
+                file.read_to_end(&mut buf).unwrap(); //TODO
+                hm.insert(
+                    target.id.clone(),
+                    Content::Article(Box::new(Article {
+                        id: target.id,
+                        permalink: target.permalink,
+                        src: target.src,
+                        meta: None,
+                        raw: String::from_utf8_lossy(&buf).into(),
+                        content: None,
+                    })),
+                );
+            } else {
+                hm.insert(
+                    target.id.clone(),
+                    Content::Asset(Asset {
+                        id: target.id,
+                        permalink: target.permalink,
+                        src: target.src,
+                    }),
+                );
+            }
+        }
         Ok(hm)
     }
-    fn get_reader(&self, src: &str) -> Box<dyn Read> {
-        let file = std::fs::File::open(format!("{}/{}", self.path.to_str().unwrap(), src)).unwrap();
+    fn get_reader(&self, src: &str) -> std::io::Result<Box<dyn Read>> {
+        let path = format!("{}/{}", self.path.to_str().unwrap(), src);
+        let file = std::fs::File::open(&path).map_err(|e| with_path(e, &path))?;
         let bf = std::io::BufReader::new(file);
-        Box::new(bf)
+        Ok(Box::new(bf))
     }
 }
 
 impl crate::traits::Writer for Filesystem {
-    fn get_writer(&self, permalink: &str) -> Box<dyn Write> {
-        let path =
-            std::path::PathBuf::from(format!("{}/{}", self.path.to_str().unwrap(), permalink));
+    fn get_writer(&self, permalink: &str) -> std::io::Result<Box<dyn Write>> {
+        let path = self.path.join(permalink.trim_start_matches('/'));
         if let Some(parent) = path.parent() {
-            std::fs::create_dir_all(parent).unwrap();
+            std::fs::create_dir_all(parent).map_err(|e| with_path(e, &parent.display()))?;
         }
-        Box::new(std::io::BufWriter::new(
-            std::fs::File::create(path).unwrap(),
-        ))
+        let file = std::fs::File::create(&path).map_err(|e| with_path(e, &path.display()))?;
+        Ok(Box::new(std::io::BufWriter::new(file)))
     }
 }
 
+// Adds the path an I/O error is about to its message.
+fn with_path(e: std::io::Error, path: &dyn std::fmt::Display) -> std::io::Error {
+    std::io::Error::new(e.kind(), format!("{}: {}", path, e))
+}
+
 fn recursive_read(
     path_prefix: path::PathBuf,
     path: &path::Path,
@@ -106,6 +174,145 @@ fn recursive_read(
     Ok(content)
 }
 
+/// Where a source file ends up: its id, and the permalink it's written to.
+#[derive(Clone, Debug)]
+struct Target {
+    src: String,
+    markdown: bool,
+    id: String,
+    permalink: String,
+}
+
+impl Target {
+    fn new(src: &str) -> Self {
+        let path = path::PathBuf::from(src);
+        Target {
+            src: src.to_string(),
+            markdown: Filetype::from(&path) == Filetype::Markdown,
+            id: id_from_path(&path),
+            permalink: permalink_from_path(&path),
+        }
+    }
+
+    /// The file written for this source, relative to the destination.
+    fn output(&self) -> String {
+        match self.markdown {
+            true => format!("{}.html", self.permalink.trim_start_matches('/')),
+            false => self.permalink.trim_start_matches('/').to_string(),
+        }
+    }
+
+    /// The same target at a suffixed permalink, e.g. `/my-note-2`.
+    fn with_permalink_suffix(&self, n: usize) -> Target {
+        Target {
+            permalink: suffix(&self.permalink, n, !self.markdown),
+            ..self.clone()
+        }
+    }
+
+    /// The same target with a suffixed id, e.g. `my-note-2`.
+    fn with_id_suffix(&self, n: usize) -> Target {
+        Target {
+            id: suffix(&self.id, n, !self.markdown),
+            ..self.clone()
+        }
+    }
+}
+
+// Adds `-{n}` to the last segment of a path, before its extension if asked.
+fn suffix(path: &str, n: usize, before_extension: bool) -> String {
+    let (folder, name) = match path.rsplit_once('/') {
+        Some((folder, name)) => (format!("{}/", folder), name),
+        None => (String::new(), path),
+    };
+    match name.rsplit_once('.') {
+        Some((stem, extension)) if before_extension && !stem.is_empty() => {
+            format!("{}{}-{}.{}", folder, stem, n, extension)
+        }
+        _ => format!("{}{}-{}", folder, name, n),
+    }
+}
+
+// Finds the sources sharing an id or an output file, or whose output file is
+// a folder other outputs live in, e.g. a file `foo` next to a folder `foo`.
+// `reserved` holds the files written by anything else, like theme assets and
+// generated pages, by output file. Notes take precedence over assets, and
+// otherwise sources are taken in alphabetical order, so the same one always
+// keeps its permalink.
+fn resolve(
+    mut targets: Vec<Target>,
+    reserved: &HashMap<String, String>,
+    collisions: Collisions,
+) -> Result<Vec<Target>, Error> {
+    targets.sort_by(|a, b| b.markdown.cmp(&a.markdown).then_with(|| a.src.cmp(&b.src)));
+
+    let mut folders: HashMap<String, String> = HashMap::new();
+    for target in &targets {
+        let output = target.output();
+        let mut current = output.as_str();
+        while let Some((folder, _)) = current.rsplit_once('/') {
+            folders
+                .entry(folder.to_string())
+                .or_insert_with(|| target.src.clone());
+            current = folder;
+        }
+    }
+
+    // Whatever got to an id or an output file first.
+    let mut ids: HashMap<String, String> = HashMap::new();
+    let mut outputs: HashMap<String, String> = reserved.clone();
+    let output_owner = |outputs: &HashMap<String, String>, target: &Target| {
+        outputs
+            .get(&target.output())
+            .or_else(|| folders.get(&target.output()))
+            .cloned()
+    };
+
+    let mut results = vec![];
+    for mut target in targets {
+        let mut clashes = vec![];
+        if let Some(other) = ids.get(&target.id) {
+            let clash = format!("{} and {} share the id {}", other, target.src, target.id);
+            match collisions {
+                Collisions::Error => clashes.push(clash),
+                Collisions::Suffix => {
+                    let n = (2..)
+                        .find(|&n| !ids.contains_key(&target.with_id_suffix(n).id))
+                        .unwrap();
+                    target = target.with_id_suffix(n);
+                    eprintln!("{}, giving {} the id {}", clash, target.src, target.id);
+                }
+            }
+        }
+        if let Some(other) = output_owner(&outputs, &target) {
+            let clash = format!(
+                "{} and {} both end up at {}",
+                other, target.src, target.permalink
+            );
+            match collisions {
+                Collisions::Error => clashes.push(clash),
+                Collisions::Suffix => {
+                    let n = (2..)
+                        .find(|&n| {
+                            output_owner(&outputs, &target.with_permalink_suffix(n)).is_none()
+                        })
+                        .unwrap();
+                    target = target.with_permalink_suffix(n);
+                    eprintln!("{}, moving {} to {}", clash, target.src, target.permalink);
+                }
+            }
+        }
+        if !clashes.is_empty() {
+            results.extend(clashes.into_iter().map(|clash| Err(Error::Output(clash))));
+            continue;
+        }
+        ids.insert(target.id.clone(), target.src.clone());
+        outputs.insert(target.output(), target.src.clone());
+        results.push(Ok(target));
+    }
+    crate::error::collect(results)
+}
+
 #[derive(Debug, PartialEq)]
 enum Filetype {
     Markdown,
@@ -149,12 +356,84 @@ fn slugify_path(path: &path::Path) -> path::PathBuf {
     new_path.set_extension("");
     new_path = new_path
         .iter()
-        .map(|fragment| {
-            return slug::slugify(fragment.to_str().unwrap_or(""));
-        })
+        .map(|fragment| slug::slugify(fragment.to_str().unwrap_or("")))
         .collect::<path::PathBuf>();
     if let Some(extension) = ext {
         new_path.set_extension(extension);
     }
     new_path
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{resolve, Collisions, Target};
+    use std::collections::HashMap;
+
+    fn targets(srcs: &[&str]) -> Vec<Target> {
+        srcs.iter().map(|src| Target::new(src)).collect()
+    }
+
+    #[test]
+    fn resolve_reports_collisions() {
+        let srcs = [
+            "My Note.md",
+            "my-note.md",
+            "notes/a b.md",
+            "notes/A-B.md",
+            "assets/main.js",
+            "x.png",
+        ];
+        let mut reserved = HashMap::new();
+        reserved.insert(
+            "assets/main.js".to_string(),
+            "the theme's main.js".to_string(),
+        );
+        match resolve(targets(&srcs), &reserved, Collisions::Error) {
+            Err(crate::error::Error::Multiple(errors)) => {
+                assert_eq!(
+                    errors[0].to_string(),
+                    "Output error: My Note.md and my-note.md both end up at /my-note"
+                );
+                assert_eq!(
+                    errors[2].to_string(),
+                    "Output error: the theme's main.js and assets/main.js both end up at /assets/main.js"
+                );
+                assert_eq!(errors.len(), 3);
+            }
+            other => panic!(
+                "expected three collisions, got {:?}",
+                other.map(|t| t.len())
+            ),
+        }
+    }
+
+    #[test]
+    fn resolve_adds_suffixes() {
+        let srcs = [
+            "my-note.md",
+            "My Note.md",
+            "foo.md",
+            "foo",
+            "foo/bar.png",
+            "pic.png",
+            "Pic.png",
+        ];
+        let resolved = resolve(targets(&srcs), &HashMap::new(), Collisions::Suffix).unwrap();
+        let targets: Vec<(&str, &str, &str)> = resolved
+            .iter()
+            .map(|t| (t.src.as_str(), t.id.as_str(), t.permalink.as_str()))
+            .collect();
+        assert_eq!(
+            targets,
+            vec![
+                ("My Note.md", "My Note", "/my-note"),
+                ("foo.md", "foo", "/foo"),
+                ("my-note.md", "my-note", "/my-note-2"),
+                ("Pic.png", "Pic.png", "/pic.png"),
+                ("foo", "foo-2", "/foo-2"),
+                ("foo/bar.png", "foo/bar.png", "/foo/bar.png"),
+                ("pic.png", "pic.png", "/pic-2.png"),
+            ]
+        );
+    }
+}
@@ -0,0 +1,88 @@
+/// Footnotes
+///
+/// Comrak renders footnotes as part of the whole document, but we render
+/// articles a top level node at a time. We therefore render footnote
+/// references and definitions ourselves, collecting the definitions into a
+/// single section at the end of the article body, marked up with the DPUB-ARIA
+/// roles screen readers understand.
+use comrak::nodes::{AstNode, NodeValue};
+use comrak::{format_html, ComrakOptions};
+use std::collections::HashMap;
+
+/// Rewrites all footnote references into raw HTML. Comrak numbers references
+/// after the footnote they point to, so repeated references get a suffix to
+/// keep their ids unique.
+pub fn rewrite_references<'a>(root: &'a AstNode<'a>) {
+    let mut seen: HashMap<Vec<u8>, usize> = HashMap::new();
+
+    for node in root.descendants() {
+        let mut data = node.data.borrow_mut();
+        let name = match data.value {
+            NodeValue::FootnoteReference(ref name) => name.clone(),
+            _ => continue,
+        };
+        let count = seen.entry(name.clone()).or_insert(0);
+        *count += 1;
+
+        let name = String::from_utf8_lossy(&name);
+        let id = match count {
+            1 => format!("fnref{}", name),
+            n => format!("fnref{}-{}", name, n),
+        };
+        let html = format!(
+            "<sup class=\"footnote-ref\"><a href=\"#fn{}\" id=\"{}\" role=\"doc-noteref\">{}</a></sup>",
+            name, id, name
+        );
+        data.value = NodeValue::HtmlInline(html.into_bytes());
+    }
+}
+
+/// Renders a single footnote definition as a list item, with a link back to
+/// its first reference.
+pub fn render_definition<'a>(node: &'a AstNode<'a>, name: &[u8], opts: &ComrakOptions) -> String {
+    let name = String::from_utf8_lossy(name);
+    let mut html = String::new();
+    for child in node.children() {
+        let mut child_html = vec![];
+        format_html(child, opts, &mut child_html).unwrap();
+        html.push_str(&String::from_utf8_lossy(&child_html));
+    }
+
+    let backref = format!(
+        "<a href=\"#fnref{}\" class=\"footnote-backref\" role=\"doc-backlink\" aria-label=\"Back to reference {}\">↩</a>",
+        name, name
+    );
+    // Comrak already leaves a space for the backref in the last paragraph.
+    match html.strip_suffix("</p>\n") {
+        Some(rest) => html = format!("{}{}</p>\n", rest, backref),
+        None => html.push_str(&backref),
+    }
+
+    format!("<li id=\"fn{}\">\n{}</li>\n", name, html)
+}
+
+/// Wraps rendered footnote definitions into the footnotes section.
+pub fn section(definitions: &str) -> String {
+    format!(
+        "<section class=\"footnotes\" role=\"doc-endnotes\" aria-label=\"Footnotes\">\n<ol>\n{}</ol>\n</section>\n",
+        definitions
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::MarkdownOptions;
+    use crate::content::parse_raw;
+
+    #[test]
+    fn footnotes_are_collected_at_the_end() {
+        let raw = "A[^x] and B[^x].\n\n[^x]: The note.\n\nAfter.";
+        let content = parse_raw(raw, &MarkdownOptions::default()).unwrap();
+
+        assert!(content.body.contains("id=\"fnref1\""));
+        assert!(content.body.contains("id=\"fnref1-2\""));
+        assert!(content.body.ends_with(
+            "<li id=\"fn1\">\n<p>The note. <a href=\"#fnref1\" class=\"footnote-backref\" role=\"doc-backlink\" aria-label=\"Back to reference 1\">↩</a></p>\n</li>\n</ol>\n</section>\n"
+        ));
+    }
+}
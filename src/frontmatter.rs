@@ -12,13 +12,19 @@ pub struct Frontmatter {
     //
     pub title: Option<String>,
     //
+    pub summary: Option<String>,
+    //
     pub tags: Option<Vec<String>>,
     //
     pub template: Option<String>,
+    // The sort order of a section, in its index note
+    pub sort: Option<String>,
     //
     pub created_at: Option<DateTime<Utc>>,
     //
     pub updated_at: Option<DateTime<Utc>>,
+    // Drafts are published, but left out of the sitemap
+    pub draft: Option<bool>,
 }
 
 // Splits the incoming bytes into a Frontmatter object and the leftover bytes.
@@ -58,9 +64,12 @@ unsupported_key:
             super::Frontmatter {
                 tags: Some(vec!["some-tag".to_string()]),
                 title: None,
+                summary: None,
                 template: Some("main".to_string()),
+                sort: None,
                 created_at: None,
                 updated_at: None,
+                draft: None,
             }
         );
         assert_eq!(new_bytes, "# Here comes the markdown!");
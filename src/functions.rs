@@ -0,0 +1,280 @@
+/// Functions
+///
+/// Tera functions and filters for querying the garden from templates, so
+/// that themes can build lists and cross-references on their own:
+///
+/// - `get_article(id=...)` an article's summary, along with its `meta` and
+///   rendered `content`.
+/// - `backlinks(id=...)` summaries of the articles linking to an article.
+/// - `tagged(tag=...)` summaries of the articles filed under a tag.
+/// - `asset_url(path=...)` the URL of a theme or source asset.
+/// - `markdown` renders a string of Markdown, e.g. from the frontmatter.
+use crate::config::MarkdownOptions;
+use crate::content::{
+    parse_raw, resolve_references, sort_summaries, Article, ArticleSummary, Content, SortKey,
+};
+use crate::tags::{self, TagGroup};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use tera::{Error, Result, Tera, Value};
+
+#[derive(Serialize)]
+struct ArticleData<'a> {
+    #[serde(flatten)]
+    summary: ArticleSummary,
+    meta: &'a Option<crate::frontmatter::Frontmatter>,
+    content: &'a Option<crate::content::ArticleContent>,
+}
+
+/// Everything the functions look things up in, computed once per build.
+#[derive(Default)]
+pub struct Lookup {
+    /// Articles by id.
+    articles: HashMap<String, Value>,
+    /// Summaries of the articles linking to an article, by id.
+    backlinks: HashMap<String, Value>,
+    /// Summaries of the articles filed under a tag, by slug.
+    tagged: HashMap<String, Value>,
+    /// URLs of theme and source assets, by path.
+    assets: HashMap<String, String>,
+}
+
+impl Lookup {
+    pub fn new(
+        contents: &HashMap<String, Content>,
+        published: &[&Article],
+        references: &HashMap<String, Vec<String>>,
+        tags: &BTreeMap<String, TagGroup>,
+        tag_sort: SortKey,
+        assets: HashMap<String, String>,
+    ) -> Self {
+        let summaries: HashMap<&str, ArticleSummary> = published
+            .iter()
+            .map(|a| (a.id.as_str(), a.summary()))
+            .collect();
+        let list = |ids: &mut dyn Iterator<Item = &String>, sort: SortKey| {
+            let mut list: Vec<&ArticleSummary> =
+                ids.filter_map(|id| summaries.get(id.as_str())).collect();
+            list.sort_by_key(|s| &s.id);
+            list.dedup_by_key(|s| &s.id);
+            let mut list: Vec<ArticleSummary> = list.into_iter().cloned().collect();
+            sort_summaries(&mut list, sort);
+            tera::to_value(list).unwrap()
+        };
+
+        let references = resolve_references(contents, references);
+        let mut linked_from: HashMap<&String, Vec<&String>> = HashMap::new();
+        for (source, targets) in &references {
+            for target in targets.iter().filter(|target| *target != source) {
+                linked_from.entry(target).or_default().push(source);
+            }
+        }
+
+        Lookup {
+            articles: published
+                .iter()
+                .map(|a| {
+                    let data = ArticleData {
+                        summary: summaries[a.id.as_str()].clone(),
+                        meta: &a.meta,
+                        content: &a.content,
+                    };
+                    (a.id.clone(), tera::to_value(data).unwrap())
+                })
+                .collect(),
+            backlinks: published
+                .iter()
+                .map(|a| {
+                    let sources = linked_from.get(&a.id).map_or(&[][..], |s| &s[..]);
+                    let backlinks = list(&mut sources.iter().copied(), SortKey::Title);
+                    (a.id.clone(), backlinks)
+                })
+                .collect(),
+            tagged: tags
+                .iter()
+                .map(|(slug, group)| (slug.clone(), list(&mut group.articles.iter(), tag_sort)))
+                .collect(),
+            assets,
+        }
+    }
+
+    // Like `content::get_article`, ids may leave out leading folders.
+    fn find<'a, T>(map: &'a HashMap<String, T>, id: &str) -> Option<&'a T> {
+        map.get(id).or_else(|| {
+            map.iter()
+                .find(|(key, _)| key.ends_with(id))
+                .map(|(_, value)| value)
+        })
+    }
+}
+
+fn string_arg<'a>(args: &'a HashMap<String, Value>, function: &str, name: &str) -> Result<&'a str> {
+    args.get(name).and_then(Value::as_str).ok_or_else(|| {
+        Error::msg(format!(
+            "`{}` requires a string `{}` argument",
+            function, name
+        ))
+    })
+}
+
+/// Registers all functions and filters with the renderer.
+pub fn register(renderer: &mut Tera, lookup: Lookup, markdown: &MarkdownOptions) {
+    let lookup = Arc::new(lookup);
+
+    let data = lookup.clone();
+    renderer.register_function("get_article", move |args: &HashMap<String, Value>| {
+        let id = string_arg(args, "get_article", "id")?;
+        Lookup::find(&data.articles, id)
+            .cloned()
+            .ok_or_else(|| Error::msg(format!("`get_article`: no article with id \"{}\"", id)))
+    });
+
+    let data = lookup.clone();
+    renderer.register_function("backlinks", move |args: &HashMap<String, Value>| {
+        let id = string_arg(args, "backlinks", "id")?;
+        Lookup::find(&data.backlinks, id)
+            .cloned()
+            .ok_or_else(|| Error::msg(format!("`backlinks`: no article with id \"{}\"", id)))
+    });
+
+    let data = lookup.clone();
+    renderer.register_function("tagged", move |args: &HashMap<String, Value>| {
+        let tag = string_arg(args, "tagged", "tag")?;
+        let tag = tag.trim_start_matches('#');
+        Ok(data
+            .tagged
+            .get(&tags::slugify(tag))
+            .cloned()
+            .unwrap_or_else(|| Value::Array(vec![])))
+    });
+
+    let data = lookup;
+    renderer.register_function("asset_url", move |args: &HashMap<String, Value>| {
+        let path = string_arg(args, "asset_url", "path")?;
+        data.assets
+            .get(path.trim_start_matches('/'))
+            .map(|url| Value::String(url.clone()))
+            .ok_or_else(|| Error::msg(format!("`asset_url`: no asset at \"{}\"", path)))
+    });
+
+    let opts = markdown.clone();
+    renderer.register_filter(
+        "markdown",
+        move |value: &Value, args: &HashMap<String, Value>| {
+            let text = value
+                .as_str()
+                .ok_or_else(|| Error::msg("`markdown` can only render strings"))?;
+            let content = parse_raw(text, &opts).unwrap_or_default();
+            let html = format!(
+                "{}{}{}",
+                content.title.unwrap_or_default(),
+                content.ingress.unwrap_or_default(),
+                content.body
+            );
+            let inline = args.get("inline").and_then(Value::as_bool).unwrap_or(false);
+            Ok(Value::String(if inline {
+                unwrap_paragraph(&html)
+            } else {
+                html
+            }))
+        },
+    );
+}
+
+// Strips the paragraph around a single line of Markdown, for use in headings
+// and the like.
+fn unwrap_paragraph(html: &str) -> String {
+    let trimmed = html.trim_end();
+    match trimmed
+        .strip_prefix("<p>")
+        .and_then(|rest| rest.strip_suffix("</p>"))
+    {
+        Some(inner) if !inner.contains("<p>") => inner.to_string(),
+        _ => html.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Lookup;
+    use crate::config::MarkdownOptions;
+    use crate::content::{Article, Content, SortKey};
+    use crate::tags::TagGroup;
+    use std::collections::{BTreeMap, HashMap};
+    use tera::{Context, Tera};
+
+    fn article(id: &str) -> Article {
+        Article {
+            id: id.to_string(),
+            permalink: format!("/{}", id),
+            src: format!("{}.md", id),
+            meta: None,
+            content: None,
+            raw: String::new(),
+        }
+    }
+
+    fn render(template: &str) -> String {
+        let articles = [article("notes/a"), article("b"), article("c")];
+        let published: Vec<&Article> = articles.iter().collect();
+        let contents: HashMap<String, Content> = articles
+            .iter()
+            .map(|a| (a.id.clone(), Content::Article(Box::new(a.clone()))))
+            .collect();
+        let mut references = HashMap::new();
+        references.insert("b".to_string(), vec!["notes/a".to_string()]);
+        // Links may leave out leading folders.
+        references.insert("c".to_string(), vec!["a".to_string()]);
+        let mut tags = BTreeMap::new();
+        tags.insert(
+            "rust".to_string(),
+            TagGroup {
+                name: "Rust".to_string(),
+                articles: vec!["c".to_string()],
+            },
+        );
+        let mut assets = HashMap::new();
+        assets.insert("main.js".to_string(), "/assets/main.js".to_string());
+        let lookup = Lookup::new(
+            &contents,
+            &published,
+            &references,
+            &tags,
+            SortKey::Title,
+            assets,
+        );
+
+        let mut tera = Tera::default();
+        super::register(&mut tera, lookup, &MarkdownOptions::default());
+        tera.render_str(template, &Context::new()).unwrap()
+    }
+
+    #[test]
+    fn functions_query_articles() {
+        assert_eq!(
+            render("{% set a = get_article(id=\"a\") %}{{ a.permalink }}"),
+            "/notes/a"
+        );
+        assert_eq!(
+            render("{% for a in backlinks(id=\"notes/a\") %}{{ a.id }} {% endfor %}"),
+            "b c "
+        );
+        assert_eq!(
+            render("{% for a in tagged(tag=\"#Rust\") %}{{ a.id }}{% endfor %}"),
+            "c"
+        );
+        assert_eq!(
+            render("{{ asset_url(path=\"/main.js\") }}"),
+            "/assets/main.js"
+        );
+    }
+
+    #[test]
+    fn markdown_filter_renders() {
+        assert_eq!(
+            render("{{ \"Some *emphasis*\" | markdown(inline=true) | safe }}"),
+            "Some <em>emphasis</em>"
+        );
+    }
+}
@@ -0,0 +1,292 @@
+/// Highlight
+///
+/// Build-time syntax highlighting of fenced code blocks.
+use std::collections::HashSet;
+use syntect::easy::HighlightLines;
+use syntect::highlighting::{Theme, ThemeSet};
+use syntect::html::{
+    css_for_theme_with_class_style, line_tokens_to_classed_spans, styled_line_to_highlighted_html,
+    ClassStyle, IncludeBackground,
+};
+use syntect::parsing::{ParseState, ScopeStack, SyntaxReference, SyntaxSet};
+use syntect::util::LinesWithEndings;
+
+lazy_static! {
+    static ref SYNTAXES: SyntaxSet = SyntaxSet::load_defaults_newlines();
+    static ref THEMES: ThemeSet = ThemeSet::load_defaults();
+}
+
+// All highlighting classes are prefixed to avoid clashing with the theme's own CSS.
+const CLASS_STYLE: ClassStyle = ClassStyle::SpacedPrefixed { prefix: "hl-" };
+
+pub const DEFAULT_THEME: &str = "base16-ocean.dark";
+
+/// How highlighted code is styled in the generated markup.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HighlightStyle {
+    /// Emit CSS classes, paired with a generated stylesheet.
+    Classes,
+    /// Emit inline `style` attributes taken from the theme.
+    Inline,
+    /// Leave code blocks untouched.
+    None,
+}
+
+impl std::str::FromStr for HighlightStyle {
+    type Err = crate::error::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "classes" => Ok(HighlightStyle::Classes),
+            "inline" => Ok(HighlightStyle::Inline),
+            "none" => Ok(HighlightStyle::None),
+            _ => Err(crate::error::Error::Config(format!(
+                "unknown highlight style \"{}\"",
+                s
+            ))),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct HighlightOptions {
+    pub style: HighlightStyle,
+    pub theme: String,
+    pub line_numbers: bool,
+}
+
+impl Default for HighlightOptions {
+    fn default() -> Self {
+        HighlightOptions {
+            style: HighlightStyle::Classes,
+            theme: DEFAULT_THEME.to_string(),
+            line_numbers: false,
+        }
+    }
+}
+
+impl HighlightOptions {
+    /// Fails if the configured theme isn't one of the bundled syntect themes.
+    pub fn validate(&self) -> Result<(), crate::error::Error> {
+        if self.style != HighlightStyle::None && !THEMES.themes.contains_key(&self.theme) {
+            let mut available: Vec<&String> = THEMES.themes.keys().collect();
+            available.sort();
+            return Err(crate::error::Error::Config(format!(
+                "unknown highlight theme \"{}\" (available: {:?})",
+                self.theme, available
+            )));
+        }
+        Ok(())
+    }
+
+    fn theme(&self) -> &'static Theme {
+        THEMES
+            .themes
+            .get(&self.theme)
+            .unwrap_or_else(|| &THEMES.themes[DEFAULT_THEME])
+    }
+}
+
+/// The parsed info string of a fenced code block, e.g. ```` ```rust {3,5-7} ````.
+#[derive(Debug, Default, PartialEq)]
+pub struct CodeInfo {
+    pub lang: Option<String>,
+    pub highlighted: HashSet<usize>,
+    pub line_numbers: bool,
+}
+
+pub fn parse_info(info: &str) -> CodeInfo {
+    let mut code_info = CodeInfo::default();
+    let mut words = info.split_whitespace().peekable();
+
+    if let Some(first) = words.peek() {
+        if !first.starts_with('{') {
+            code_info.lang = words.next().map(|l| l.to_string());
+        }
+    }
+
+    for word in words {
+        if word == "linenos" {
+            code_info.line_numbers = true;
+            continue;
+        }
+        let ranges = word.trim_start_matches('{').trim_end_matches('}');
+        for range in ranges.split(',').map(str::trim) {
+            let mut bounds = range.splitn(2, '-').map(|n| n.trim().parse::<usize>());
+            match (bounds.next(), bounds.next()) {
+                (Some(Ok(start)), Some(Ok(end))) => code_info.highlighted.extend(start..=end),
+                (Some(Ok(line)), None) => {
+                    code_info.highlighted.insert(line);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    code_info
+}
+
+/// Returns the stylesheet for the configured theme, if the style calls for one.
+pub fn stylesheet(opts: &HighlightOptions) -> Option<String> {
+    if opts.style != HighlightStyle::Classes {
+        return None;
+    }
+    css_for_theme_with_class_style(opts.theme(), CLASS_STYLE).ok()
+}
+
+/// Renders a code block into a complete `<pre>` element.
+pub fn render(code: &str, info: &str, opts: &HighlightOptions) -> String {
+    let code_info = parse_info(info);
+    let syntax = code_info
+        .lang
+        .as_ref()
+        .and_then(|lang| SYNTAXES.find_syntax_by_token(lang))
+        .unwrap_or_else(|| SYNTAXES.find_syntax_plain_text());
+
+    let lines = match opts.style {
+        HighlightStyle::Inline => inline_lines(code, syntax, opts.theme()),
+        _ => classed_lines(code, syntax),
+    }
+    .unwrap_or_else(|_| {
+        LinesWithEndings::from(code)
+            .map(tera::escape_html)
+            .collect()
+    });
+
+    let line_numbers = opts.line_numbers || code_info.line_numbers;
+    let body: String = lines
+        .iter()
+        .enumerate()
+        .map(|(i, line)| {
+            let number = i + 1;
+            let class = if code_info.highlighted.contains(&number) {
+                "line hl"
+            } else {
+                "line"
+            };
+            if line_numbers {
+                format!(
+                    "<span class=\"{}\"><span class=\"line-number\">{}</span>{}</span>",
+                    class, number, line
+                )
+            } else {
+                format!("<span class=\"{}\">{}</span>", class, line)
+            }
+        })
+        .collect();
+
+    let pre_style = match (opts.style, opts.theme().settings.background) {
+        (HighlightStyle::Inline, Some(c)) => format!(
+            " style=\"background-color:#{:02x}{:02x}{:02x};\"",
+            c.r, c.g, c.b
+        ),
+        _ => String::new(),
+    };
+    let code_class = match &code_info.lang {
+        Some(lang) => format!(" class=\"language-{}\"", tera::escape_html(lang)),
+        None => String::new(),
+    };
+
+    format!(
+        "<pre class=\"highlight\"{}><code{}>{}</code></pre>\n",
+        pre_style, code_class, body
+    )
+}
+
+fn inline_lines(
+    code: &str,
+    syntax: &SyntaxReference,
+    theme: &Theme,
+) -> Result<Vec<String>, syntect::Error> {
+    let mut highlighter = HighlightLines::new(syntax, theme);
+    LinesWithEndings::from(code)
+        .map(|line| {
+            let regions = highlighter.highlight_line(line, &SYNTAXES)?;
+            styled_line_to_highlighted_html(&regions, IncludeBackground::No)
+        })
+        .collect()
+}
+
+// Classed spans may stay open across lines, so every line closes whatever
+// it left open and the next line re-opens it. That keeps each line
+// self-contained, which line numbers and highlighted lines rely on.
+fn classed_lines(code: &str, syntax: &SyntaxReference) -> Result<Vec<String>, syntect::Error> {
+    let mut state = ParseState::new(syntax);
+    let mut stack = ScopeStack::new();
+    let mut open: Vec<String> = vec![];
+    let mut lines = vec![];
+
+    for line in LinesWithEndings::from(code) {
+        let ops = state.parse_line(line, &SYNTAXES)?;
+        let (html, _) = line_tokens_to_classed_spans(line, &ops, CLASS_STYLE, &mut stack)?;
+        let mut out = open.concat();
+        out.push_str(&html);
+        track_spans(&html, &mut open);
+        out.push_str(&"</span>".repeat(open.len()));
+        lines.push(out);
+    }
+
+    Ok(lines)
+}
+
+// Text content is escaped by syntect, so every `<` starts a span tag.
+fn track_spans(html: &str, open: &mut Vec<String>) {
+    let mut rest = html;
+    while let Some(i) = rest.find('<') {
+        rest = &rest[i..];
+        if rest.starts_with("</span>") {
+            open.pop();
+            rest = &rest["</span>".len()..];
+        } else {
+            let end = rest.find('>').map_or(rest.len(), |e| e + 1);
+            open.push(rest[..end].to_string());
+            rest = &rest[end..];
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_info_handles_line_annotations() {
+        let info = parse_info("rust {3,5-7} linenos");
+
+        assert_eq!(info.lang, Some("rust".to_string()));
+        assert_eq!(
+            info.highlighted,
+            vec![3, 5, 6, 7].into_iter().collect::<HashSet<usize>>()
+        );
+        assert!(info.line_numbers);
+    }
+
+    #[test]
+    fn parse_info_handles_missing_lang() {
+        let info = parse_info("{2}");
+
+        assert_eq!(info.lang, None);
+        assert!(info.highlighted.contains(&2));
+    }
+
+    #[test]
+    fn render_keeps_lines_self_contained() {
+        let opts = HighlightOptions {
+            line_numbers: true,
+            ..HighlightOptions::default()
+        };
+        let html = render("/* a\nb */\nfn x() {}\n", "rust {2}", &opts);
+
+        assert!(html.starts_with("<pre class=\"highlight\"><code class=\"language-rust\">"));
+        assert_eq!(
+            html.matches("<span").count(),
+            html.matches("</span>").count()
+        );
+
+        // The comment spans two lines, so the second line re-opens it.
+        let second = html.split("<span class=\"line hl\">").nth(1).unwrap();
+        let second = &second[..second.find('\n').unwrap()];
+        assert!(second.starts_with("<span class=\"line-number\">2</span>"));
+        assert!(second.contains("hl-comment"));
+    }
+}
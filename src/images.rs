@@ -0,0 +1,340 @@
+/// Images
+///
+/// Build-time processing of the images in our source folder. Every image is
+/// resized into a set of narrower variants (optionally with WebP versions
+/// alongside), and images embedded in articles get the matching `srcset`,
+/// `sizes`, dimensions and lazy loading. Variants are cached by the hash of
+/// their source image, so only new or changed images are resized.
+use crate::cache::{self, Cache};
+use crate::content::Asset;
+use crate::error::Error;
+use crate::traits::Writer;
+use comrak::nodes::{AstNode, NodeValue};
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::FilterType;
+use image::metadata::Orientation;
+use image::{DynamicImage, ImageDecoder, ImageFormat, ImageReader};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io::{Cursor, Write};
+
+const CACHE_NAMESPACE: &str = "images";
+
+#[derive(Clone, Debug)]
+pub struct ImageOptions {
+    pub enabled: bool,
+    /// Widths of the generated variants, in pixels.
+    pub widths: Vec<u32>,
+    pub webp: bool,
+    /// Encoding quality of JPEG and WebP variants, from 1 to 100.
+    pub quality: u8,
+    /// The `sizes` attribute given to embedded images.
+    pub sizes: String,
+}
+
+impl Default for ImageOptions {
+    fn default() -> Self {
+        ImageOptions {
+            enabled: true,
+            widths: vec![480, 960, 1600],
+            webp: false,
+            quality: 80,
+            sizes: "100vw".to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Variant {
+    pub width: u32,
+    pub height: u32,
+    pub permalink: String,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ProcessedImage {
+    pub width: u32,
+    pub height: u32,
+    pub variants: Vec<Variant>,
+    pub webp: Vec<Variant>,
+}
+
+/// All processed images of a build, keyed by the permalink of their source.
+#[derive(Clone, Debug, Default)]
+pub struct Images {
+    pub sizes: String,
+    pub processed: HashMap<String, ProcessedImage>,
+}
+
+impl ImageOptions {
+    pub fn validate(&self) -> Result<(), Error> {
+        if !(1..=100).contains(&self.quality) {
+            return Err(Error::Config(format!(
+                "image quality must be between 1 and 100, got {}",
+                self.quality
+            )));
+        }
+        if self.widths.contains(&0) {
+            return Err(Error::Config("image widths must be above 0".to_string()));
+        }
+        Ok(())
+    }
+}
+
+pub fn is_image(permalink: &str) -> bool {
+    let ext = permalink.rsplit('.').next().unwrap_or("").to_lowercase();
+    matches!(ext.as_str(), "jpg" | "jpeg" | "png")
+}
+
+/// Generates and writes the variants of a single image.
+pub fn process(
+    asset: &Asset,
+    bytes: &[u8],
+    dst: &dyn Writer,
+    opts: &ImageOptions,
+    cache: Option<&Cache>,
+) -> Result<ProcessedImage, Error> {
+    let format = ImageFormat::from_path(&asset.permalink)?;
+    let mut decoder = ImageReader::with_format(Cursor::new(bytes), format).into_decoder()?;
+    let orientation = decoder.orientation()?;
+    let (width, height) = match (decoder.dimensions(), orientation) {
+        ((w, h), Orientation::Rotate90)
+        | ((w, h), Orientation::Rotate270)
+        | ((w, h), Orientation::Rotate90FlipH)
+        | ((w, h), Orientation::Rotate270FlipH) => (h, w),
+        (dimensions, _) => dimensions,
+    };
+
+    // Only generate variants narrower than the original. The original itself
+    // is only offered if it's no larger than the widest variant we'd generate.
+    let max_width = opts.widths.iter().copied().max().unwrap_or(0);
+    let mut widths: Vec<u32> = opts.widths.iter().copied().filter(|&w| w < width).collect();
+    if width <= max_width {
+        widths.push(width);
+    }
+    widths.sort_unstable();
+    widths.dedup();
+
+    // Decoding is expensive, so it's only done on a cache miss.
+    let mut decoded: Option<DynamicImage> = None;
+    let mut decoder = Some(decoder);
+    let source_hash = cache::key(&[bytes]);
+
+    let mut variants = vec![];
+    let mut webp = vec![];
+    for w in widths {
+        let h = ((height as u64 * w as u64) as f64 / width as f64)
+            .round()
+            .max(1.0) as u32;
+        let mut formats = vec![format];
+        if opts.webp {
+            formats.push(ImageFormat::WebP);
+        }
+        for variant_format in formats {
+            let permalink = variant_permalink(&asset.permalink, w, variant_format);
+            let key = cache::key(&[
+                source_hash.as_bytes(),
+                &w.to_le_bytes(),
+                variant_format.extensions_str()[0].as_bytes(),
+                &[opts.quality],
+            ]);
+            let encoded = match cache.and_then(|c| c.get(CACHE_NAMESPACE, &key)) {
+                Some(encoded) => encoded,
+                None => {
+                    if decoded.is_none() {
+                        if let Some(d) = decoder.take() {
+                            let mut image = DynamicImage::from_decoder(d)?;
+                            image.apply_orientation(orientation);
+                            decoded = Some(image);
+                        }
+                    }
+                    let resized = match &decoded {
+                        Some(image) if w == width => image.clone(),
+                        Some(image) => image.resize_exact(w, h, FilterType::Lanczos3),
+                        None => continue,
+                    };
+                    let encoded = encode(&resized, variant_format, opts.quality)?;
+                    if let Some(c) = cache {
+                        c.put(CACHE_NAMESPACE, &key, &encoded);
+                    }
+                    encoded
+                }
+            };
+            dst.get_writer(&permalink)?.write_all(&encoded)?;
+
+            let variant = Variant {
+                width: w,
+                height: h,
+                permalink,
+            };
+            match variant_format {
+                ImageFormat::WebP => webp.push(variant),
+                _ => variants.push(variant),
+            }
+        }
+    }
+
+    Ok(ProcessedImage {
+        width,
+        height,
+        variants,
+        webp,
+    })
+}
+
+fn encode(image: &DynamicImage, format: ImageFormat, quality: u8) -> Result<Vec<u8>, Error> {
+    let mut bytes = vec![];
+    match format {
+        ImageFormat::Jpeg => {
+            JpegEncoder::new_with_quality(&mut bytes, quality)
+                .encode_image(&DynamicImage::ImageRgb8(image.to_rgb8()))?;
+        }
+        ImageFormat::WebP => {
+            let rgba = image.to_rgba8();
+            let encoder = webp::Encoder::from_rgba(rgba.as_raw(), rgba.width(), rgba.height());
+            bytes = encoder.encode(quality as f32).to_vec();
+        }
+        _ => image.write_to(&mut Cursor::new(&mut bytes), format)?,
+    }
+    Ok(bytes)
+}
+
+// `/photos/cat.jpg` becomes e.g. `/photos/cat-480w.jpg` or `/photos/cat-480w.webp`.
+fn variant_permalink(permalink: &str, width: u32, format: ImageFormat) -> String {
+    let (stem, ext) = permalink.rsplit_once('.').unwrap_or((permalink, ""));
+    let ext = match format {
+        ImageFormat::WebP => "webp",
+        _ => ext,
+    };
+    format!("{}-{}w.{}", stem, width, ext)
+}
+
+fn srcset(variants: &[Variant]) -> String {
+    variants
+        .iter()
+        .map(|v| format!("{} {}w", v.permalink, v.width))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+/// Replaces every image in the document with lazily loaded markup, pointing
+/// processed images at their variants.
+pub fn transform<'a>(root: &'a AstNode<'a>, images: &Images) {
+    for node in root.descendants() {
+        let (url, title) = match node.data.borrow().value {
+            NodeValue::Image(ref link) => (
+                String::from_utf8_lossy(&link.url).to_string(),
+                String::from_utf8_lossy(&link.title).to_string(),
+            ),
+            _ => continue,
+        };
+        let alt: String = node
+            .descendants()
+            .filter_map(|n| match n.data.borrow().value {
+                NodeValue::Text(ref t) => Some(String::from_utf8_lossy(t).to_string()),
+                _ => None,
+            })
+            .collect();
+        while let Some(child) = node.first_child() {
+            child.detach();
+        }
+
+        let html = render(&url, &alt, &title, images);
+        node.data.borrow_mut().value = NodeValue::HtmlInline(html.into_bytes());
+    }
+}
+
+fn render(url: &str, alt: &str, title: &str, images: &Images) -> String {
+    let title = match title {
+        "" => String::new(),
+        t => format!(" title=\"{}\"", tera::escape_html(t)),
+    };
+    let alt = tera::escape_html(alt);
+
+    let processed = if url.starts_with('/') {
+        images.processed.get(url)
+    } else {
+        images.processed.get(&format!("/{}", url))
+    };
+    let largest = processed.and_then(|p| p.variants.last().map(|v| (p, v)));
+    let (image, src) = match largest {
+        Some(found) => found,
+        None => {
+            return format!(
+                "<img src=\"{}\" alt=\"{}\"{} loading=\"lazy\" decoding=\"async\" />",
+                tera::escape_html(url),
+                alt,
+                title
+            )
+        }
+    };
+
+    let sizes = tera::escape_html(&images.sizes);
+    let img = format!(
+        "<img src=\"{}\" srcset=\"{}\" sizes=\"{}\" width=\"{}\" height=\"{}\" alt=\"{}\"{} loading=\"lazy\" decoding=\"async\" />",
+        src.permalink,
+        srcset(&image.variants),
+        sizes,
+        src.width,
+        src.height,
+        alt,
+        title
+    );
+    if image.webp.is_empty() {
+        return img;
+    }
+    format!(
+        "<picture><source type=\"image/webp\" srcset=\"{}\" sizes=\"{}\" />{}</picture>",
+        srcset(&image.webp),
+        sizes,
+        img
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn variant_permalink_keeps_path() {
+        assert_eq!(
+            variant_permalink("/photos/cat.jpg", 480, ImageFormat::Jpeg),
+            "/photos/cat-480w.jpg"
+        );
+        assert_eq!(
+            variant_permalink("/photos/cat.jpg", 480, ImageFormat::WebP),
+            "/photos/cat-480w.webp"
+        );
+    }
+
+    #[test]
+    fn render_points_at_variants() {
+        let variant = |width, height| Variant {
+            width,
+            height,
+            permalink: format!("/cat-{}w.jpg", width),
+        };
+        let mut images = Images {
+            sizes: "100vw".to_string(),
+            ..Images::default()
+        };
+        images.processed.insert(
+            "/cat.jpg".to_string(),
+            ProcessedImage {
+                width: 2000,
+                height: 1000,
+                variants: vec![variant(480, 240), variant(960, 480)],
+                webp: vec![],
+            },
+        );
+
+        assert_eq!(
+            render("/cat.jpg", "A cat", "", &images),
+            "<img src=\"/cat-960w.jpg\" srcset=\"/cat-480w.jpg 480w, /cat-960w.jpg 960w\" sizes=\"100vw\" width=\"960\" height=\"480\" alt=\"A cat\" loading=\"lazy\" decoding=\"async\" />"
+        );
+        assert_eq!(
+            render("/dog.gif", "", "", &images),
+            "<img src=\"&#x2F;dog.gif\" alt=\"\" loading=\"lazy\" decoding=\"async\" />"
+        );
+    }
+}
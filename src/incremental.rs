@@ -0,0 +1,266 @@
+/// Incremental
+///
+/// Remembers what went into the previous build of a destination, so that the
+/// next one only re-renders pages whose inputs changed: notes that changed,
+/// the notes they link to or are linked from, and the tag and section pages
+/// listing them. Everything is re-rendered when the templates, the settings
+/// or the site-wide data every page gets, like `site.articles` or `totals`,
+/// change.
+use crate::cache::{self, Cache};
+use crate::tags;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashSet};
+
+const NAMESPACE: &str = "builds";
+
+/// Templates rendered in every build anyway, whose data doesn't matter here.
+pub const ALWAYS_RENDERED: &[&str] = &["404.html", "tags.html", "tasks.html"];
+
+lazy_static! {
+    // `totals` and `tasks` as variables of their own, not fields of another,
+    // like a note's `content.tasks`.
+    static ref TOTALS: Regex = Regex::new(r"(?:^|[^\w.])totals\b").unwrap();
+    static ref TASKS: Regex = Regex::new(r"(?:^|[^\w.])tasks\b").unwrap();
+    // Counts of any note but the one being rendered, e.g. `note.word_count`
+    // in a loop over `site.articles`, as opposed to `content.word_count`.
+    static ref COUNTS: Regex =
+        Regex::new(r"(\w+)\.(?:word_count|reading_time|char_count)\b").unwrap();
+}
+
+const COUNT_FIELDS: &[&str] = &["word_count", "reading_time", "char_count"];
+
+/// The part of the data every template gets that incrementally rendered pages
+/// depend on, given the source of their templates. Totals, open tasks and
+/// word counts change with almost any edit, so they're left out unless the
+/// templates use them, or every edit would render everything again.
+pub fn shared_data(mut data: Value, templates: &str) -> Value {
+    if let Value::Object(map) = &mut data {
+        if !TOTALS.is_match(templates) {
+            map.remove("totals");
+        }
+        if !TASKS.is_match(templates) {
+            map.remove("tasks");
+        }
+    }
+    let uses_counts = COUNTS
+        .captures_iter(templates)
+        .any(|cap| &cap[1] != "content");
+    if !uses_counts {
+        remove_counts(&mut data);
+    }
+    data
+}
+
+fn remove_counts(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for field in COUNT_FIELDS {
+                map.remove(*field);
+            }
+            map.values_mut().for_each(remove_counts);
+        }
+        Value::Array(values) => values.iter_mut().for_each(remove_counts),
+        _ => {}
+    }
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Manifest {
+    /// A hash of the templates, settings and site-wide data, which every
+    /// page depends on.
+    pub fingerprint: String,
+    pub notes: BTreeMap<String, Note>,
+    /// Stamps of the copied assets, by output path.
+    pub assets: BTreeMap<String, String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct Note {
+    /// A hash of the note's frontmatter and rendered content.
+    pub hash: String,
+    /// The ids of the notes it links to.
+    pub links: Vec<String>,
+    /// The slugs of its tags.
+    pub tags: Vec<String>,
+}
+
+impl Manifest {
+    /// The manifest of the previous build to `destination`, if any.
+    pub fn load(cache: &Cache, destination: &str) -> Option<Manifest> {
+        let bytes = cache.get(NAMESPACE, &cache::key(&[destination.as_bytes()]))?;
+        serde_json::from_slice(&bytes).ok()
+    }
+
+    pub fn save(&self, cache: &Cache, destination: &str) {
+        let bytes = serde_json::to_vec(self).unwrap();
+        cache.put(NAMESPACE, &cache::key(&[destination.as_bytes()]), &bytes);
+    }
+}
+
+/// What needs to be rendered again.
+#[derive(Debug, Default)]
+pub struct Plan {
+    full: bool,
+    notes: HashSet<String>,
+    tags: HashSet<String>,
+    sections: HashSet<String>,
+}
+
+fn folders(id: &str) -> impl Iterator<Item = &str> {
+    id.match_indices('/').map(move |(i, _)| &id[..i])
+}
+
+impl Plan {
+    /// A plan rendering everything.
+    pub fn full() -> Self {
+        Plan {
+            full: true,
+            ..Plan::default()
+        }
+    }
+
+    /// Compares the previous build with the current one.
+    pub fn new(previous: Option<&Manifest>, current: &Manifest) -> Self {
+        let previous = match previous {
+            Some(previous) if previous.fingerprint == current.fingerprint => previous,
+            _ => return Plan::full(),
+        };
+
+        let changed: HashSet<&String> = current
+            .notes
+            .iter()
+            .filter(|(id, note)| previous.notes.get(*id).map(|n| &n.hash) != Some(&note.hash))
+            .map(|(id, _)| id)
+            .chain(
+                previous
+                    .notes
+                    .keys()
+                    .filter(|id| !current.notes.contains_key(*id)),
+            )
+            .collect();
+
+        let mut plan = Plan::default();
+        for id in &changed {
+            plan.notes.insert(id.to_string());
+            for note in [previous.notes.get(*id), current.notes.get(*id)]
+                .iter()
+                .flatten()
+            {
+                // Their backlinks changed.
+                plan.notes.extend(note.links.iter().cloned());
+                for slug in &note.tags {
+                    let mut slug = Some(slug.as_str());
+                    while let Some(s) = slug {
+                        plan.tags.insert(s.to_string());
+                        slug = tags::parent(s);
+                    }
+                }
+            }
+            if let Some(name) = id.strip_prefix("tags/") {
+                plan.tags.insert(tags::slugify(name));
+            }
+            plan.sections.extend(folders(id).map(str::to_string));
+        }
+        // Notes linking to changed notes.
+        for (id, note) in &current.notes {
+            if note.links.iter().any(|link| changed.contains(link)) {
+                plan.notes.insert(id.clone());
+            }
+        }
+        plan
+    }
+
+    pub fn note(&self, id: &str) -> bool {
+        self.full || self.notes.contains(id)
+    }
+
+    pub fn tag(&self, slug: &str) -> bool {
+        self.full || self.tags.contains(slug)
+    }
+
+    pub fn section(&self, path: &str) -> bool {
+        self.full || self.sections.contains(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Manifest, Note, Plan};
+    use serde_json::json;
+
+    #[test]
+    fn shared_data_leaves_out_unused_counts() {
+        let data = json!({
+            "site": {"articles": [{"id": "a", "word_count": 3}]},
+            "tasks": [],
+            "totals": {"word_count": 3},
+        });
+
+        let own = "{{ content.word_count }} {% for t in content.tasks %}{% endfor %}";
+        assert_eq!(
+            super::shared_data(data.clone(), own),
+            json!({"site": {"articles": [{"id": "a"}]}})
+        );
+
+        let listing =
+            "{% for n in site.articles %}{{ n.word_count }}{% endfor %}{{ totals.word_count }}";
+        assert_eq!(
+            super::shared_data(data.clone(), listing),
+            json!({"site": {"articles": [{"id": "a", "word_count": 3}]}, "totals": {"word_count": 3}})
+        );
+    }
+
+    fn note(hash: &str, links: &[&str], tags: &[&str]) -> Note {
+        Note {
+            hash: hash.to_string(),
+            links: links.iter().map(|l| l.to_string()).collect(),
+            tags: tags.iter().map(|t| t.to_string()).collect(),
+        }
+    }
+
+    fn manifest(notes: Vec<(&str, Note)>) -> Manifest {
+        Manifest {
+            fingerprint: "templates".to_string(),
+            notes: notes
+                .into_iter()
+                .map(|(id, note)| (id.to_string(), note))
+                .collect(),
+            ..Manifest::default()
+        }
+    }
+
+    #[test]
+    fn plan_follows_dependents() {
+        let previous = manifest(vec![
+            ("journal/a", note("1", &["b"], &["lang/rust"])),
+            ("b", note("2", &[], &[])),
+            ("c", note("3", &["journal/a"], &[])),
+            ("d", note("4", &[], &["go"])),
+        ]);
+        let current = manifest(vec![
+            ("journal/a", note("5", &["b"], &["lang/rust"])),
+            ("b", note("2", &[], &[])),
+            ("c", note("3", &["journal/a"], &[])),
+            ("d", note("4", &[], &["go"])),
+        ]);
+        let plan = Plan::new(Some(&previous), &current);
+
+        assert!(plan.note("journal/a") && plan.note("b") && plan.note("c"));
+        assert!(!plan.note("d"));
+        assert!(plan.tag("lang/rust") && plan.tag("lang") && !plan.tag("go"));
+        assert!(plan.section("journal"));
+    }
+
+    #[test]
+    fn plan_renders_everything_when_templates_change() {
+        let previous = manifest(vec![("a", note("1", &[], &[]))]);
+        let mut current = manifest(vec![("a", note("1", &[], &[]))]);
+        assert!(!Plan::new(Some(&previous), &current).note("a"));
+
+        current.fingerprint = "other templates".to_string();
+        assert!(Plan::new(Some(&previous), &current).note("a"));
+        assert!(Plan::new(None, &current).note("a"));
+    }
+}
@@ -1,10 +1,11 @@
+use crate::comments;
 use crate::content::{get_article, get_asset};
 use crate::content::{Article, Content};
 use regex::{Regex, RegexSet};
 use std::collections::HashMap;
 
 lazy_static! {
-  static ref SET: RegexSet = RegexSet::new(&[
+  static ref SET: RegexSet = RegexSet::new([
       // Image links
       r"!\[\[[\w\s/\.-_&]+?\]\]",
       // Aliased links
@@ -69,12 +70,14 @@ pub fn replace(
 }
 
 pub fn extract(contents: &HashMap<String, Content>, article: &Article) -> Vec<String> {
-    let matches: Vec<_> = SET.matches(&article.raw).into_iter().collect();
+    // Links within comments aren't published, so they don't count either.
+    let raw = comments::strip(&article.raw);
+    let matches: Vec<_> = SET.matches(&raw).into_iter().collect();
     let mut links = vec![];
 
     // If there are image matches, replace them.
     if matches.contains(&0) {
-        IMAGE.captures_iter(&article.raw).for_each(|cap| {
+        IMAGE.captures_iter(&raw).for_each(|cap| {
             if get_asset(contents, &cap[1]).is_some() {
                 links.push(cap[1].to_string());
             }
@@ -83,7 +86,7 @@ pub fn extract(contents: &HashMap<String, Content>, article: &Article) -> Vec<St
 
     // Replace aliased links
     if matches.contains(&1) {
-        ALIAS.captures_iter(&article.raw).for_each(|cap| {
+        ALIAS.captures_iter(&raw).for_each(|cap| {
             if get_article(contents, &cap[1]).is_some() {
                 links.push(cap[1].to_string());
             }
@@ -92,7 +95,7 @@ pub fn extract(contents: &HashMap<String, Content>, article: &Article) -> Vec<St
 
     // Replace normal links
     if matches.contains(&2) {
-        NORMAL.captures_iter(&article.raw).for_each(|cap| {
+        NORMAL.captures_iter(&raw).for_each(|cap| {
             if get_article(contents, &cap[1]).is_some() {
                 links.push(cap[1].to_string());
             }
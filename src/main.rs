@@ -1,19 +1,43 @@
 use crate::traits::{Reader, Writer};
-use clap::{App, Arg};
+use clap::{App, Arg, ArgMatches};
+use config::{Config, ConfigFile, MarkdownOptions};
 use content::Content;
 use filesystem::Filesystem;
 use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use rayon::prelude::*;
 use std::collections::hash_map::Entry;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::Read;
 use std::path;
 use std::sync::mpsc::channel;
 use std::time::{Duration, Instant};
 use tera::Tera;
+mod cache;
+mod callouts;
+mod comments;
+mod config;
 mod content;
+mod diagrams;
 mod error;
+mod feeds;
 mod filesystem;
+mod footnotes;
 mod frontmatter;
+mod functions;
+mod highlight;
+mod images;
+mod incremental;
 mod links;
+mod marks;
+mod math;
+mod output;
+mod pagination;
+mod scan;
+mod sections;
+mod site;
+mod sitemap;
+mod tags;
+mod tasks;
 mod traits;
 #[macro_use]
 extern crate lazy_static;
@@ -23,7 +47,16 @@ extern crate lazy_static;
 /// We then iterate over them once to build a map and parse out internal references.
 /// After that we iterate over the graph once more in order to process, render and write the new files.
 ///
-fn main() -> Result<(), error::Error> {
+fn main() {
+    // Errors are printed with `Display`, so that every error of a parallel
+    // step shows up on a line of its own.
+    if let Err(e) = run() {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+fn run() -> Result<(), error::Error> {
     let matches = App::new("Spade")
         .version("0.1.0-alpha")
         .about("digital gardening tool")
@@ -32,7 +65,6 @@ fn main() -> Result<(), error::Error> {
                 .long("source")
                 .short("s")
                 .takes_value(true)
-                .required(true)
                 .help("Sets the source folder path"),
         )
         .arg(
@@ -40,7 +72,6 @@ fn main() -> Result<(), error::Error> {
                 .long("destination")
                 .short("d")
                 .takes_value(true)
-                .required(true)
                 .help("Sets the destination folder path"),
         )
         .arg(
@@ -48,33 +79,355 @@ fn main() -> Result<(), error::Error> {
                 .short("t")
                 .long("theme")
                 .takes_value(true)
-                .required(true)
                 .help("Sets the theme folder path"),
         )
+        .arg(
+            Arg::with_name("config")
+                .long("config")
+                .short("c")
+                .takes_value(true)
+                .help("Sets the config file path, instead of looking for spade.toml or spade.yaml in the current folder"),
+        )
         .arg(
             Arg::with_name("watch")
                 .short("w")
                 .long("watch")
                 .help("Re-generate the site whenever the source or theme directories change"),
         )
+        .arg(
+            Arg::with_name("full-rebuild")
+                .long("full-rebuild")
+                .help("Render every page, rather than only those that changed since the previous build"),
+        )
+        .arg(
+            Arg::with_name("clean")
+                .long("clean")
+                .help("Empty the destination folder first, unless it holds files Spade didn't create"),
+        )
         .arg(
             Arg::with_name("allow-html")
                 .long("allow-html")
                 .help("Don't encode HTML entities within the markdown contents"),
         )
+        .arg(
+            Arg::with_name("no-allow-html")
+                .long("no-allow-html")
+                .conflicts_with("allow-html")
+                .help("Encode HTML entities within the markdown contents, overriding the config file"),
+        )
+        .arg(
+            Arg::with_name("count-code-blocks")
+                .long("count-code-blocks")
+                .help("Include code blocks in word counts and reading times"),
+        )
+        .arg(
+            Arg::with_name("no-count-code-blocks")
+                .long("no-count-code-blocks")
+                .conflicts_with("count-code-blocks")
+                .help("Leave code blocks out of word counts and reading times, overriding the config file"),
+        )
+        .arg(
+            Arg::with_name("diagram-renderer")
+                .long("diagram-renderer")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("lang=command")
+                .help("Renders diagram code blocks of a language to SVG with a command, e.g. dot=\"dot -Tsvg\""),
+        )
+        .arg(
+            Arg::with_name("cache")
+                .long("cache")
+                .takes_value(true)
+                .default_value(cache::DEFAULT_DIR)
+                .help("Sets the folder where rendered artifacts are cached between builds"),
+        )
+        .arg(
+            Arg::with_name("enable-extension")
+                .long("enable-extension")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .possible_values(config::EXTENSION_NAMES)
+                .help("Turns on a Markdown extension, may be given multiple times"),
+        )
+        .arg(
+            Arg::with_name("disable-extension")
+                .long("disable-extension")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .possible_values(config::EXTENSION_NAMES)
+                .help("Turns off a Markdown extension, may be given multiple times"),
+        )
+        .arg(
+            Arg::with_name("highlight-style")
+                .long("highlight-style")
+                .takes_value(true)
+                .possible_values(&["classes", "inline", "none"])
+                .default_value("classes")
+                .help("Sets how code blocks are highlighted: CSS classes, inline styles or not at all"),
+        )
+        .arg(
+            Arg::with_name("highlight-theme")
+                .long("highlight-theme")
+                .takes_value(true)
+                .default_value(highlight::DEFAULT_THEME)
+                .help("Sets the syntax highlighting theme"),
+        )
+        .arg(
+            Arg::with_name("image-widths")
+                .long("image-widths")
+                .takes_value(true)
+                .value_name("widths")
+                .default_value("480,960,1600")
+                .help("Sets the widths, in pixels, of the resized variants generated for images"),
+        )
+        .arg(
+            Arg::with_name("image-quality")
+                .long("image-quality")
+                .takes_value(true)
+                .default_value("80")
+                .help("Sets the encoding quality (1-100) of resized JPEG and WebP images"),
+        )
+        .arg(
+            Arg::with_name("webp")
+                .long("webp")
+                .help("Generate WebP versions of resized images"),
+        )
+        .arg(
+            Arg::with_name("no-image-processing")
+                .long("no-image-processing")
+                .help("Copy images as they are instead of generating resized variants"),
+        )
+        .arg(
+            Arg::with_name("on-collision")
+                .long("on-collision")
+                .takes_value(true)
+                .possible_values(&["error", "suffix"])
+                .default_value("error")
+                .help("Sets what to do about source files ending up at the same permalink, like \"My Note.md\" and \"my-note.md\": fail, or add a suffix to all but one"),
+        )
+        .arg(
+            Arg::with_name("tag-sort")
+                .long("tag-sort")
+                .takes_value(true)
+                .possible_values(&["title", "created", "updated"])
+                .default_value("updated")
+                .help("Sets the order of the notes listed on tag pages"),
+        )
+        .arg(
+            Arg::with_name("section-sort")
+                .long("section-sort")
+                .takes_value(true)
+                .possible_values(&["title", "created", "updated"])
+                .default_value("title")
+                .help("Sets the order of the notes listed on section pages, unless a section's index note sets its own"),
+        )
+        .arg(
+            Arg::with_name("per-page")
+                .long("per-page")
+                .takes_value(true)
+                .default_value("0")
+                .help("Sets the number of notes per page of tag and section pages, 0 for no pagination"),
+        )
+        .arg(
+            Arg::with_name("base-url")
+                .long("base-url")
+                .takes_value(true)
+                .help("Sets the URL the site is published at, e.g. https://example.com, which feeds need for absolute links"),
+        )
+        .arg(
+            Arg::with_name("feed-title")
+                .long("feed-title")
+                .takes_value(true)
+                .help("Sets the title of the site feed"),
+        )
+        .arg(
+            Arg::with_name("feed-limit")
+                .long("feed-limit")
+                .takes_value(true)
+                .default_value("20")
+                .help("Sets the maximum number of notes in a feed"),
+        )
+        .arg(
+            Arg::with_name("feed-summaries")
+                .long("feed-summaries")
+                .help("Only include note summaries in feeds, rather than full notes"),
+        )
+        .arg(
+            Arg::with_name("rss")
+                .long("rss")
+                .help("Generate RSS 2.0 feeds alongside the Atom feeds"),
+        )
+        .arg(
+            Arg::with_name("robots-disallow")
+                .long("robots-disallow")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("Asks crawlers to stay out of a path in robots.txt, may be given multiple times"),
+        )
+        .arg(
+            Arg::with_name("line-numbers")
+                .long("line-numbers")
+                .help("Show line numbers in all highlighted code blocks"),
+        )
+        .arg(
+            Arg::with_name("no-line-numbers")
+                .long("no-line-numbers")
+                .conflicts_with("line-numbers")
+                .help("Hide line numbers in highlighted code blocks, overriding the config file"),
+        )
         .get_matches();
 
-    // These settings are all required, so let's bail early if they for some reason
-    // show up as None.
-    let src_path = matches.value_of("source").expect("Invalid source value");
-    let dst_path = matches
-        .value_of("destination")
-        .expect("Invalid destination value");
-    let theme_path = matches.value_of("theme").expect("Invalid theme path");
-    let allow_html = matches.is_present("allow-html");
+    // Flags given on the command line override the config file, which in
+    // turn overrides the flags' defaults.
+    let file = ConfigFile::load(matches.value_of("config"))?.unwrap_or_default();
+
+    // These settings are all required, so let's bail early if they're
+    // missing from both.
+    let required = |name: &str, value: &Option<String>| {
+        setting(&matches, name, value).ok_or_else(|| {
+            error::Error::Config(format!(
+                "the {} path must be given with --{} or in the config file",
+                name, name
+            ))
+        })
+    };
+    let src_path = required("source", &file.source)?;
+    let dst_path = required("destination", &file.destination)?;
+    let theme_path = required("theme", &file.theme)?;
+
+    let mut markdown = MarkdownOptions {
+        allow_html: switch(&matches, "allow-html", file.markdown.allow_html),
+        ..MarkdownOptions::default()
+    };
+    markdown.highlight.style = setting(&matches, "highlight-style", &file.markdown.highlight_style)
+        .expect("Invalid highlight style")
+        .parse()?;
+    markdown.highlight.theme = setting(&matches, "highlight-theme", &file.markdown.highlight_theme)
+        .expect("Invalid highlight theme");
+    markdown.highlight.line_numbers = switch(&matches, "line-numbers", file.markdown.line_numbers);
+    markdown.count_code_blocks = switch(
+        &matches,
+        "count-code-blocks",
+        file.markdown.count_code_blocks,
+    );
+    for (name, &enabled) in &file.markdown.extensions {
+        markdown.extensions.set(name, enabled)?;
+    }
+    for (lang, command) in &file.markdown.diagram_renderers {
+        markdown
+            .diagrams
+            .renderers
+            .insert(lang.clone(), command.clone());
+    }
+    for name in matches.values_of("enable-extension").into_iter().flatten() {
+        markdown.extensions.set(name, true)?;
+    }
+    for name in matches.values_of("disable-extension").into_iter().flatten() {
+        markdown.extensions.set(name, false)?;
+    }
+    for renderer in matches.values_of("diagram-renderer").into_iter().flatten() {
+        let (lang, command) = renderer.split_once('=').ok_or_else(|| {
+            error::Error::Config(format!(
+                "invalid diagram renderer \"{}\", expected lang=command",
+                renderer
+            ))
+        })?;
+        markdown
+            .diagrams
+            .renderers
+            .insert(lang.trim().to_string(), command.trim().to_string());
+    }
+    markdown.cache = Some(cache::Cache::new(path::PathBuf::from(
+        setting(&matches, "cache", &file.cache).expect("Invalid cache path"),
+    )));
+
+    let mut images = images::ImageOptions {
+        enabled: !matches.is_present("no-image-processing"),
+        webp: matches.is_present("webp"),
+        ..images::ImageOptions::default()
+    };
+    images.widths = matches
+        .value_of("image-widths")
+        .expect("Invalid image widths")
+        .split(',')
+        .map(|w| {
+            w.trim()
+                .parse()
+                .map_err(|_| error::Error::Config(format!("invalid image width \"{}\"", w.trim())))
+        })
+        .collect::<Result<_, _>>()?;
+    images.quality = matches
+        .value_of("image-quality")
+        .expect("Invalid image quality")
+        .parse()
+        .map_err(|_| error::Error::Config("image quality must be a number".to_string()))?;
+
+    let mut feeds = feeds::FeedOptions {
+        full_content: !matches.is_present("feed-summaries"),
+        rss: matches.is_present("rss"),
+        limit: matches
+            .value_of("feed-limit")
+            .expect("Invalid feed limit")
+            .parse()
+            .map_err(|_| error::Error::Config("feed limit must be a number".to_string()))?,
+        ..feeds::FeedOptions::default()
+    };
+    if let Some(title) = setting(&matches, "feed-title", &file.title) {
+        feeds.title = title;
+    }
+
+    let base_url = setting(&matches, "base-url", &file.base_url);
+    let config = Config {
+        source: src_path.clone(),
+        destination: dst_path,
+        theme: theme_path.clone(),
+        markdown,
+        images,
+        site: config::Site {
+            base_url: base_url.clone(),
+            ..file.site()
+        },
+        base_url,
+        feeds,
+        robots: sitemap::RobotsOptions {
+            disallow: matches
+                .values_of("robots-disallow")
+                .into_iter()
+                .flatten()
+                .map(str::to_string)
+                .collect(),
+        },
+        tag_sort: matches
+            .value_of("tag-sort")
+            .expect("Invalid tag sort")
+            .parse()?,
+        per_page: matches
+            .value_of("per-page")
+            .expect("Invalid per page value")
+            .parse()
+            .map_err(|_| error::Error::Config("per page must be a number".to_string()))?,
+        section_sort: matches
+            .value_of("section-sort")
+            .expect("Invalid section sort")
+            .parse()?,
+        full_rebuild: matches.is_present("full-rebuild"),
+        collisions: setting(&matches, "on-collision", &file.on_collision)
+            .expect("Invalid collision policy")
+            .parse()?,
+    };
+    config.validate()?;
+
+    if matches.is_present("clean") {
+        let removed = output::clean(path::Path::new(&config.destination))?;
+        println!("Removed {} files from {}", removed, config.destination);
+    }
 
     // Ok 3, 2, 1, let's jam...!
-    generate_site(src_path, dst_path, theme_path, allow_html)?;
+    generate_site(&config)?;
 
     // If the `watch` flag is set, we set up a notifier and loop indefinitely
     // to re-generate the site whenever there are file changes in our
@@ -84,14 +437,14 @@ fn main() -> Result<(), error::Error> {
         let (tx, rx) = channel();
 
         let mut src_watcher: RecommendedWatcher = Watcher::new(tx.clone(), Duration::from_secs(1))?;
-        src_watcher.watch(src_path, RecursiveMode::Recursive)?;
+        src_watcher.watch(&src_path, RecursiveMode::Recursive)?;
 
         let mut theme_watcher: RecommendedWatcher = Watcher::new(tx, Duration::from_secs(1))?;
-        theme_watcher.watch(theme_path, RecursiveMode::Recursive)?;
+        theme_watcher.watch(&theme_path, RecursiveMode::Recursive)?;
 
         loop {
             if let Ok(notify::DebouncedEvent::Write(_)) = rx.recv() {
-                generate_site(src_path, dst_path, theme_path, allow_html)?;
+                generate_site(&config)?;
             }
         }
     }
@@ -99,12 +452,84 @@ fn main() -> Result<(), error::Error> {
     Ok(())
 }
 
-fn generate_site(
-    src_path: &str,
-    dst_path: &str,
-    theme_path: &str,
-    allow_html: bool,
-) -> Result<(), error::Error> {
+// The value of a flag if it's given on the command line, falling back to the
+// config file and then to the flag's default.
+fn setting(matches: &ArgMatches, name: &str, file: &Option<String>) -> Option<String> {
+    if matches.occurrences_of(name) > 0 {
+        return matches.value_of(name).map(str::to_string);
+    }
+    file.clone()
+        .or_else(|| matches.value_of(name).map(str::to_string))
+}
+
+// Like `setting`, for flags turned on with `--{name}` and off with
+// `--no-{name}`, which are off by default.
+fn switch(matches: &ArgMatches, name: &str, file: Option<bool>) -> bool {
+    if matches.is_present(name) {
+        return true;
+    }
+    if matches.is_present(format!("no-{}", name)) {
+        return false;
+    }
+    file.unwrap_or(false)
+}
+
+// A hash of what every page depends on: the templates, the settings and the
+// site-wide data every template gets, such as the list of all notes. Themes
+// calling `get_article` may show any note on any page, so for them it covers
+// every note.
+fn fingerprint(
+    config: &Config,
+    globals: &tera::Context,
+    notes: &BTreeMap<String, incremental::Note>,
+) -> Result<String, error::Error> {
+    let templates = Filesystem::new(path::PathBuf::from(format!("{}/templates", config.theme)));
+    let mut files: Vec<String> = templates
+        .read_all()?
+        .into_values()
+        .filter_map(|c| match c {
+            Content::Asset(a) => Some(a.src),
+            _ => None,
+        })
+        .collect();
+    files.sort();
+
+    let settings = format!(
+        "{:?}",
+        Config {
+            full_rebuild: false,
+            ..config.clone()
+        }
+    );
+    let mut parts = vec![
+        env!("CARGO_PKG_VERSION").as_bytes().to_vec(),
+        settings.into_bytes(),
+    ];
+    let mut used = String::new();
+    for file in files {
+        let mut bytes = vec![];
+        templates.get_reader(&file)?.read_to_end(&mut bytes)?;
+        if !incremental::ALWAYS_RENDERED.contains(&file.as_str()) {
+            used.push_str(&String::from_utf8_lossy(&bytes));
+        }
+        parts.push(file.into_bytes());
+        parts.push(bytes);
+    }
+    let data = incremental::shared_data(globals.clone().into_json(), &used);
+    parts.push(serde_json::to_vec(&data).unwrap());
+    if used.contains("get_article") {
+        parts.push(serde_json::to_vec(notes).unwrap());
+    }
+    Ok(cache::key(
+        &parts.iter().map(Vec::as_slice).collect::<Vec<_>>(),
+    ))
+}
+
+fn generate_site(config: &Config) -> Result<(), error::Error> {
+    let src_path = config.source.as_str();
+    let dst_path = config.destination.as_str();
+    let theme_path = config.theme.as_str();
+
     println!("Generating site...");
     // Start timer
     let now = Instant::now();
@@ -115,149 +540,712 @@ fn generate_site(
     // Turn off autoescape for the HTML renderer since we trust our own content.
     renderer.autoescape_on(vec![]);
 
+    // TODO ignore if the directory doesnt exist
+    let theme_assets = Filesystem::new(path::PathBuf::from(format!("{}/assets", theme_path))); //TODO validate path
+    let asset_files = theme_assets.read_all()?;
+
+    // Source files can't take the place of theme assets or generated files.
+    let has_template = |name: &str| renderer.get_template_names().any(|n| n == name);
+    let mut reserved: HashMap<String, String> = asset_files
+        .values()
+        .filter_map(|c| match c {
+            Content::Asset(a) => Some((
+                format!("assets{}", a.permalink),
+                format!("the theme's {}", a.src),
+            )),
+            _ => None,
+        })
+        .collect();
+    let mut generated = vec![
+        "404.html",
+        "assets/feed.json",
+        "assets/graph.json",
+        "assets/tasks.json",
+    ];
+    if highlight::stylesheet(&config.markdown.highlight).is_some() {
+        generated.push("assets/highlight.css");
+    }
+    if has_template("tasks.html") {
+        generated.push("tasks.html");
+    }
+    if has_template("tags.html") {
+        generated.push("tags/index.html");
+    }
+    if config.base_url.is_some() {
+        generated.extend(["atom.xml", "sitemap.xml"]);
+        if config.feeds.rss {
+            generated.push("rss.xml");
+        }
+    }
+    for file in generated {
+        reserved.insert(file.to_string(), format!("the generated {}", file));
+    }
+
     // Set up our filesystem handlers for our source and destination directories.
-    let src = Filesystem::new(path::PathBuf::from(src_path));
-    let dst = Filesystem::new(path::PathBuf::from(dst_path));
+    let src = Filesystem::new(path::PathBuf::from(src_path))
+        .on_collision(config.collisions)
+        .reserve(reserved);
+    // Everything is written through `dst`, which keeps track of the files
+    // this build produces.
+    let dst = output::Output::new(Filesystem::new(path::PathBuf::from(dst_path)));
 
-    let contents = src.read_all()?;
+    let mut contents = src.read_all()?;
     let references = content::get_references(&contents);
+
+    //
+    // Generate resized variants of all images, so that articles embedding
+    // them can point at the variants.
+    //
+    let mut markdown = config.markdown.clone();
+    markdown.images.sizes = config.images.sizes.clone();
+    if config.images.enabled {
+        let processed = contents
+            .par_iter()
+            .filter_map(|(_id, content)| match content {
+                Content::Asset(asset) if images::is_image(&asset.permalink) => Some(asset),
+                _ => None,
+            })
+            .map(|asset| {
+                let mut bytes = vec![];
+                src.get_reader(&asset.src)?.read_to_end(&mut bytes)?;
+                match images::process(
+                    asset,
+                    &bytes,
+                    &dst,
+                    &config.images,
+                    config.markdown.cache.as_ref(),
+                ) {
+                    Ok(image) => Ok(Some((asset.permalink.clone(), image))),
+                    Err(e) => {
+                        eprintln!("Unable to process image {}: {}", asset.src, e);
+                        Ok(None)
+                    }
+                }
+            })
+            .collect();
+        markdown.images.processed = error::collect(processed)?.into_iter().flatten().collect();
+    }
+
+    //
+    // Resolve internal links, parse frontmatter and render the Markdown of
+    // every article up front, since the graph and the site totals are built
+    // from the results.
+    //
+    let articles = contents
+        .par_iter()
+        .filter_map(|(_id, c)| match c {
+            Content::Article(a) => Some(content::process(&contents, a, &markdown)),
+            _ => None,
+        })
+        .collect();
+    let articles = error::collect(articles)?;
+    articles.into_iter().for_each(|article| {
+        contents.insert(article.id.clone(), Content::Article(Box::new(article)));
+    });
+
+    //
+    // Build the tag lookup map, rolling nested tags up into their parents.
+    //
     let mut tags: HashMap<String, Vec<String>> = HashMap::new();
+    contents.values().for_each(|c| {
+        if let Content::Article(article) = c {
+            article.tags().into_iter().for_each(|tag| {
+                match tags.entry(tag) {
+                    Entry::Vacant(e) => {
+                        e.insert(vec![article.id.clone()]);
+                    }
+                    Entry::Occupied(mut e) => {
+                        e.get_mut().push(article.id.clone());
+                    }
+                };
+            });
+        }
+    });
+    let tags = tags::roll_up(&tags);
+    let tag_tree = tags::tree(&tags);
+
+    // Notes in the `tags` folder describe the tag of the same name, rather
+    // than being articles of their own.
+    let tag_descriptions = tags::descriptions(&contents, &tags);
+    let description_ids: HashSet<&str> = tag_descriptions
+        .values()
+        .map(|description| description.id.as_str())
+        .collect();
+    let is_description = |id: &str| description_ids.contains(id);
+
+    //
+    // Build the listing pages of all folders, for themes that render them.
+    //
+    let sections = if renderer
+        .get_template_names()
+        .any(|name| name == "section.html")
+    {
+        sections::build(&contents, config.section_sort)
+    } else {
+        BTreeMap::new()
+    };
+    let is_section_index = |id: &str| sections::is_section_index(&sections, id);
+
+    let site_tasks = tasks::aggregate(&contents);
     let graph = content::json_graph(&contents, &references);
+    // The ids of the notes each note links to.
+    let links = content::resolve_references(&contents, &references);
+    let totals = content::totals(&contents);
 
     //
-    // Traverse the contents again to write to file, now that
-    // all internal links have been resolved.
+    // Everything every template gets, set up once and shared between renders.
     //
-    contents.iter().for_each(|(_id, content)| {
-        match content {
-            //
-            // For Markdown files, we pass the content to the templating engine,
-            // update the extension to .html and then write to file.
-            //
-            Content::Article(article) => {
-                //
-                // Resolve internal wikilinks and image links.
-                //
-                let mut article = links::replace(&contents, article).unwrap();
+    let published: Vec<&content::Article> = contents
+        .values()
+        .filter_map(|c| match c {
+            Content::Article(a) if !is_description(&a.id) && !is_section_index(&a.id) => {
+                Some(a.as_ref())
+            }
+            _ => None,
+        })
+        .collect();
+    let site = site::Site::new(
+        &config.site,
+        &published,
+        &links,
+        &tags,
+        &tag_tree,
+        &sections,
+    );
+    let mut globals = tera::Context::new();
+    globals.insert("site", &site);
+    globals.insert("tag_tree", &tag_tree);
+    globals.insert("tasks", &site_tasks);
+    globals.insert("totals", &totals);
 
-                //
-                // Split out and parse the frontmatter YAML.
-                //
-                let (meta, raw_content) = frontmatter::extract(article.raw);
-                article.meta = meta;
-                article.raw = raw_content;
-
-                let mut article_tags = vec![];
-
-                // Update the tag lookup map
-                if let Some(m) = article.meta.clone() {
-                    if let Some(t) = m.tags {
-                        t.into_iter().for_each(|tag| {
-                            match tags.entry(tag.clone()) {
-                                Entry::Vacant(e) => {
-                                    e.insert(vec![article.id.clone()]);
-                                }
-                                Entry::Occupied(mut e) => {
-                                    e.get_mut().push(article.id.clone());
-                                }
-                            };
-                            article_tags.push(tag);
-                        });
-                    }
-                }
+    //
+    // Register the functions and filters templates query the garden with.
+    //
+    let assets = |contents: &HashMap<String, Content>| {
+        contents
+            .values()
+            .filter_map(|c| match c {
+                Content::Asset(a) => Some((a.src.clone(), a.permalink.clone())),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+    };
+    // Theme assets take precedence over source assets at the same path.
+    let asset_urls = assets(&contents)
+        .into_iter()
+        .chain(
+            assets(&asset_files)
+                .into_iter()
+                .map(|(src, permalink)| (src, format!("/assets{}", permalink))),
+        )
+        .collect();
+    functions::register(
+        &mut renderer,
+        functions::Lookup::new(
+            &contents,
+            &published,
+            &references,
+            &tags,
+            config.tag_sort,
+            asset_urls,
+        ),
+        &markdown,
+    );
 
-                //
-                // Turn article content from Markdown into HTML.
-                //
-                article.content = content::parse_raw(&article.raw, allow_html);
+    //
+    // Work out what changed since the previous build, so that pages and
+    // assets that didn't can be left alone.
+    //
+    let mut manifest = incremental::Manifest::default();
+    for c in contents.values() {
+        if let Content::Article(a) = c {
+            let rendered = serde_json::to_vec(&(&a.meta, &a.content)).unwrap();
+            let note = incremental::Note {
+                hash: cache::key(&[&rendered]),
+                links: links.get(&a.id).cloned().unwrap_or_default(),
+                tags: a.tags().iter().map(|tag| tags::slugify(tag)).collect(),
+            };
+            manifest.notes.insert(a.id.clone(), note);
+        }
+    }
+    manifest.fingerprint = fingerprint(config, &globals, &manifest.notes)?;
+    let previous = match (&markdown.cache, config.full_rebuild) {
+        (Some(cache), false) => incremental::Manifest::load(cache, dst_path),
+        _ => None,
+    };
+    let plan = match config.full_rebuild {
+        true => incremental::Plan::full(),
+        false => incremental::Plan::new(previous.as_ref(), &manifest),
+    };
+    let previous_assets = previous.map(|m| m.assets).unwrap_or_default();
+    // Copies an asset, unless it's unchanged since the previous build.
+    // Returns its stamp, and whether it was copied.
+    let copy = |from: &Filesystem, src: &str, permalink: String| {
+        let stamp = from.stamp(src);
+        let unchanged = stamp.is_some()
+            && previous_assets.get(&permalink) == stamp.as_ref()
+            && dst.exists(&permalink);
+        if unchanged {
+            dst.keep(&permalink);
+        } else {
+            std::io::copy(&mut from.get_reader(src)?, &mut dst.get_writer(&permalink)?)?;
+        }
+        Ok((permalink, stamp, !unchanged))
+    };
 
+    //
+    // Render all articles in parallel, now that all internal links have been
+    // resolved. Every thread gets a rendering context of its own.
+    //
+    let rendered_notes = published
+        .par_iter()
+        .filter(|article| {
+            let path = format!("{}.html", &article.permalink);
+            let render = plan.note(&article.id) || !dst.exists(&path);
+            if !render {
+                dst.keep(&path);
+            }
+            render
+        })
+        .map_init(
+            || globals.clone(),
+            |ctx, article| {
                 //
                 // Set up rendering context.
                 //
-                let mut ctx = tera::Context::new();
                 ctx.insert("id", &article.id);
                 ctx.insert("meta", &article.meta);
                 ctx.insert("content", &article.content);
+                let article_tags: Vec<tags::Tag> = article
+                    .tags()
+                    .iter()
+                    .map(|tag| tags::Tag::new(tag))
+                    .collect();
                 ctx.insert("tags", &article_tags);
 
                 //
                 // Render our template.
                 //
-                let rendered = renderer.render("default.html", &ctx).unwrap(); //TODO
+                let rendered = renderer.render("default.html", ctx)?;
 
                 //
                 // Set up a writer for our output file and write the rendered
                 // content to it.
                 //
-                let mut w = dst.get_writer(&format!("{}.html", &article.permalink));
-                w.write_all(rendered.as_bytes())
-                    .expect("Unable to write to destination");
-            }
-            //
-            // For all other files, we just copy them over.
-            //
-            Content::Asset(asset) => {
-                std::io::copy(
-                    &mut src.get_reader(&asset.src),
-                    &mut dst.get_writer(&asset.permalink),
-                )
-                .unwrap();
-            }
+                let mut w = dst.get_writer(&format!("{}.html", &article.permalink))?;
+                w.write_all(rendered.as_bytes())?;
+                Ok(())
+            },
+        )
+        .collect();
+    let rendered_notes = error::collect(rendered_notes)?.len();
+
+    //
+    // Copy all other files from our source folder, and then the theme assets,
+    // as they are. Theme assets are copied last, so that they take precedence
+    // over source files at the same path, just like in `asset_urls`.
+    //
+    let mut copies = error::collect(
+        contents
+            .par_iter()
+            .filter_map(|(_id, c)| match c {
+                Content::Asset(asset) => Some(copy(&src, &asset.src, asset.permalink.clone())),
+                _ => None,
+            })
+            .collect(),
+    )?;
+    copies.extend(error::collect(
+        asset_files
+            .par_iter()
+            .filter_map(|(_id, c)| match c {
+                Content::Asset(asset) => Some(copy(
+                    &theme_assets,
+                    &asset.src,
+                    format!("/assets{}", &asset.permalink),
+                )),
+                _ => None,
+            })
+            .collect(),
+    )?);
+    let mut copied = 0;
+    for (permalink, stamp, was_copied) in copies {
+        if let Some(stamp) = stamp {
+            manifest.assets.insert(permalink, stamp);
         }
-    });
+        if was_copied {
+            copied += 1;
+        }
+    }
 
-    // Move theme assets
-    // TODO ignore if the directory doesnt exist
-    let theme_assets = Filesystem::new(path::PathBuf::from(format!("{}/assets", theme_path))); //TODO validate path
-    let asset_files = theme_assets.read_all()?;
-    asset_files
-        .into_iter()
-        .filter_map(|(_k, c)| match c {
-            Content::Asset(a) => Some(a),
-            _ => None,
-        })
-        .for_each(|asset| {
-            std::io::copy(
-                &mut theme_assets.get_reader(&asset.src),
-                &mut dst.get_writer(&format!("/assets/{}", &asset.permalink)),
-            )
-            .unwrap();
-        });
+    // Write the highlighting stylesheet
+    if let Some(css) = highlight::stylesheet(&config.markdown.highlight) {
+        let mut w = dst.get_writer("/assets/highlight.css")?;
+        w.write_all(css.as_bytes())
+            .expect("Unable to write highlight.css to destination");
+    }
 
     // Write graph.json
-    let mut w = dst.get_writer("/assets/graph.json");
+    let mut w = dst.get_writer("/assets/graph.json")?;
     w.write_all(graph.as_bytes())
         .expect("Unable to write graph.json to destination");
 
+    // Write tasks.json
+    let mut w = dst.get_writer("/assets/tasks.json")?;
+    w.write_all(serde_json::to_string(&site_tasks).unwrap().as_bytes())
+        .expect("Unable to write tasks.json to destination");
+
+    // Render and write the open tasks page, for themes that have one
+    if renderer
+        .get_template_names()
+        .any(|name| name == "tasks.html")
+    {
+        let open_tasks: Vec<&tasks::SiteTask> =
+            site_tasks.iter().filter(|task| !task.task.done).collect();
+        let mut ctx = globals.clone();
+        ctx.insert("open_tasks", &open_tasks);
+        let rendered = renderer.render("tasks.html", &ctx)?;
+        let mut w = dst.get_writer("/tasks.html")?;
+        w.write_all(rendered.as_bytes())
+            .expect("Unable to write tasks.html to destination");
+    }
+
     // Write the 404 page
-    let mut w = dst.get_writer("/404.html");
-    let rendered_404 = renderer
-        .render("404.html", &tera::Context::default())
-        .unwrap();
+    let mut w = dst.get_writer("/404.html")?;
+    let rendered_404 = renderer.render("404.html", &globals).unwrap();
     w.write_all(rendered_404.as_bytes())
         .expect("Unable to write 404.html to destination");
 
     // Render and write tags pages
-    tags.iter().for_each(|(tag, article_ids)| {
-        let mut ctx = tera::Context::new();
-        let link_map: HashMap<String, String> =
-            article_ids.iter().fold(HashMap::new(), |mut m, id| {
-                if let Some(article) = content::get_article(&contents, id) {
-                    m.insert((article.id).to_string(), (article.permalink).to_string());
+    let rendered_tags = tags
+        .par_iter()
+        .filter(|(slug, group)| {
+            let base = tags::url(slug);
+            let render = plan.tag(slug) || !dst.exists(&format!("{}.html", base));
+            if !render {
+                let count = group
+                    .articles
+                    .iter()
+                    .filter(|id| !is_description(id))
+                    .count();
+                for page in 1..=pagination::count(count, config.per_page) {
+                    dst.keep(&format!("{}.html", pagination::page_url(&base, page)));
                 }
-                m
-            });
-        ctx.insert("tag", &tag);
-        ctx.insert("links", &link_map);
-        let rendered = renderer.render("tag.html", &ctx).unwrap(); //TODO
-        let mut w = dst.get_writer(&format!("/tags/{}.html", tag));
-        w.write_all(rendered.as_bytes())
-            .expect("Unable to write graph.json to destination");
+            }
+            render
+        })
+        .map_init(
+            || globals.clone(),
+            |ctx, (slug, group)| {
+                let link_map: HashMap<String, String> = group
+                    .articles
+                    .iter()
+                    .filter(|id| !is_description(id))
+                    .fold(HashMap::new(), |mut m, id| {
+                        if let Some(article) = content::get_article(&contents, id) {
+                            m.insert((article.id).to_string(), (article.permalink).to_string());
+                        }
+                        m
+                    });
+                let mut articles: Vec<content::ArticleSummary> = group
+                    .articles
+                    .iter()
+                    .filter(|id| !is_description(id))
+                    .filter_map(|id| content::get_article(&contents, id))
+                    .map(|article| article.summary())
+                    .collect();
+                content::sort_summaries(&mut articles, config.tag_sort);
+                ctx.insert("tag", &group.name);
+                ctx.insert("slug", &slug);
+                ctx.insert(
+                    "description",
+                    &tag_descriptions
+                        .get(slug)
+                        .and_then(|description| description.content.as_ref()),
+                );
+                ctx.insert("links", &link_map);
+                ctx.insert(
+                    "parent",
+                    &tags::parent(slug).and_then(|parent| tags::find(&tag_tree, parent)),
+                );
+                ctx.insert(
+                    "children",
+                    &tags::find(&tag_tree, slug).map_or(&[][..], |node| &node.children[..]),
+                );
+
+                let base = tags::url(slug);
+                for (paginator, page) in pagination::paginate(&articles, config.per_page, &base) {
+                    ctx.insert("articles", &page);
+                    ctx.insert("paginator", &paginator);
+                    let rendered = renderer.render("tag.html", ctx)?;
+                    let mut w = dst.get_writer(&format!(
+                        "{}.html",
+                        pagination::page_url(&base, paginator.current)
+                    ))?;
+                    w.write_all(rendered.as_bytes())?;
+                }
+                Ok(())
+            },
+        )
+        .collect();
+    let rendered_tags = error::collect(rendered_tags)?.len();
+
+    // Write feed.json
+    let articles = contents.values().filter_map(|c| match c {
+        Content::Article(a) if !is_description(&a.id) && !is_section_index(&a.id) => {
+            Some(a.as_ref())
+        }
+        _ => None,
     });
+    let json_feed = feeds::json_feed(articles, config.base_url.as_deref(), &config.feeds);
+    let mut w = dst.get_writer("/assets/feed.json")?;
+    w.write_all(json_feed.as_bytes())
+        .expect("Unable to write feed.json to destination");
+
+    // Write the site and tag feeds
+    if let Some(base_url) = &config.base_url {
+        let articles = contents.values().filter_map(|c| match c {
+            Content::Article(a) if !is_description(&a.id) && !is_section_index(&a.id) => {
+                Some(a.as_ref())
+            }
+            _ => None,
+        });
+        let mut site_feeds = vec![feeds::Feed::new(
+            config.feeds.title.clone(),
+            "",
+            articles,
+            &config.feeds,
+        )];
+        for (slug, group) in tags.iter() {
+            let articles = group
+                .articles
+                .iter()
+                .filter(|id| !is_description(id))
+                .filter_map(|id| content::get_article(&contents, id));
+            site_feeds.push(feeds::Feed::new(
+                format!("{}: #{}", config.feeds.title, group.name),
+                &tags::url(slug),
+                articles,
+                &config.feeds,
+            ));
+        }
+        for feed in site_feeds {
+            let mut w = dst.get_writer(&feed.atom_path())?;
+            w.write_all(feed.atom(base_url, &config.feeds).as_bytes())
+                .expect("Unable to write feed to destination");
+            if config.feeds.rss {
+                let mut w = dst.get_writer(&feed.rss_path())?;
+                w.write_all(feed.rss(base_url, &config.feeds).as_bytes())
+                    .expect("Unable to write feed to destination");
+            }
+        }
+    }
+
+    // Render and write section pages
+    let rendered_sections = sections
+        .par_iter()
+        .filter(|(path, section)| {
+            let render =
+                plan.section(path) || !dst.exists(&sections::page_path(&section.permalink, 1));
+            if !render {
+                for page in 1..=pagination::count(section.articles.len(), config.per_page) {
+                    dst.keep(&sections::page_path(&section.permalink, page));
+                }
+            }
+            render
+        })
+        .map_init(
+            || globals.clone(),
+            |ctx, (_path, section)| {
+                let index = section
+                    .index
+                    .as_ref()
+                    .and_then(|id| content::get_article(&contents, id));
+                ctx.insert("meta", &index.and_then(|a| a.meta.as_ref()));
+                ctx.insert("content", &index.and_then(|a| a.content.as_ref()));
+
+                let pages =
+                    pagination::paginate(&section.articles, config.per_page, &section.permalink);
+                for (paginator, page) in pages {
+                    let section = sections::Section {
+                        articles: page.to_vec(),
+                        ..section.clone()
+                    };
+                    ctx.insert("section", &section);
+                    ctx.insert("paginator", &paginator);
+                    let rendered = renderer.render("section.html", ctx)?;
+                    let path = sections::page_path(&section.permalink, paginator.current);
+                    let mut w = dst.get_writer(&path)?;
+                    w.write_all(rendered.as_bytes())?;
+                }
+                Ok(())
+            },
+        )
+        .collect();
+    let rendered_sections = error::collect(rendered_sections)?.len();
+
+    // Render and write the tag index, for themes that have one
+    if renderer
+        .get_template_names()
+        .any(|name| name == "tags.html")
+    {
+        if tags.contains_key("index") {
+            eprintln!("The tag index replaces the tag page of #index");
+        }
+        let mut ctx = globals.clone();
+        ctx.insert("tags", &tags::flatten(&tag_tree));
+        let rendered = renderer.render("tags.html", &ctx)?;
+        let mut w = dst.get_writer("/tags/index.html")?;
+        w.write_all(rendered.as_bytes())
+            .expect("Unable to write tags/index.html to destination");
+    }
+
+    // Write the sitemap and robots.txt
+    if let Some(base_url) = &config.base_url {
+        let mut entries: Vec<sitemap::Entry> = contents
+            .values()
+            .filter_map(|c| match c {
+                Content::Article(a)
+                    if !a.is_draft() && !is_description(&a.id) && !is_section_index(&a.id) =>
+                {
+                    Some(sitemap::Entry::from_article(a))
+                }
+                _ => None,
+            })
+            .collect();
+        for (slug, group) in tags.iter() {
+            let articles: Vec<content::ArticleSummary> = group
+                .articles
+                .iter()
+                .filter_map(|id| content::get_article(&contents, id))
+                .map(|article| article.summary())
+                .collect();
+            entries.push(sitemap::Entry::new(
+                &tags::url(slug),
+                sitemap::latest(&articles),
+            ));
+        }
+        for section in sections.values() {
+            entries.push(sitemap::Entry::new(
+                &format!("{}/", section.permalink),
+                sitemap::latest(&section.articles),
+            ));
+        }
+        for (template, permalink) in [("tags.html", "/tags/"), ("tasks.html", "/tasks")] {
+            if renderer.get_template_names().any(|name| name == template) {
+                entries.push(sitemap::Entry::new(permalink, None));
+            }
+        }
+        let mut w = dst.get_writer("/sitemap.xml")?;
+        w.write_all(sitemap::sitemap(base_url, &entries).as_bytes())
+            .expect("Unable to write sitemap.xml to destination");
 
+        // A robots.txt of our own takes precedence.
+        let has_robots = contents
+            .values()
+            .any(|c| matches!(c, Content::Asset(a) if a.permalink == "/robots.txt"));
+        if !has_robots {
+            let mut w = dst.get_writer("/robots.txt")?;
+            w.write_all(sitemap::robots(base_url, &config.robots).as_bytes())
+                .expect("Unable to write robots.txt to destination");
+        }
+    }
+
+    if let Some(cache) = &markdown.cache {
+        manifest.save(cache, dst_path);
+    }
+
+    // Remove whatever the previous build produced that this one didn't, e.g.
+    // the pages of deleted or renamed notes.
+    let produced = dst.files();
+    let dst_dir = path::Path::new(dst_path);
+    if let Some(previous) = output::load(dst_dir) {
+        let removed = output::remove_stale(dst_dir, &previous, &produced)?;
+        if removed > 0 {
+            println!("Removed {} stale files", removed);
+        }
+    }
+    output::save(dst_dir, &produced)?;
+    println!(
+        "Rendered {} of {} notes, {} of {} tag pages and {} of {} section pages, copied {} of {} assets",
+        rendered_notes,
+        published.len(),
+        rendered_tags,
+        tags.len(),
+        rendered_sections,
+        sections.len(),
+        copied,
+        manifest.assets.len()
+    );
     println!(
         "Site generated in {} milliseconds",
         now.elapsed().as_millis()
     );
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{config, content, feeds, filesystem, generate_site, images, sitemap, Config};
+    use std::fs;
+    use std::path::Path;
+
+    fn write(dir: &Path, name: &str, text: &str) {
+        fs::write(dir.join(name), text).unwrap();
+    }
+
+    #[test]
+    fn editing_a_note_only_renders_it_and_its_neighbours() {
+        let dir = std::env::temp_dir().join(format!("spade-incremental-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let src = dir.join("content");
+        fs::create_dir_all(&src).unwrap();
+        write(&src, "a.md", "# A\n\nSee [[b]].\n");
+        write(
+            &src,
+            "b.md",
+            "# B\n\nFirst paragraph.\n\nSecond paragraph.\n",
+        );
+        write(&src, "c.md", "# C\n\nSee [[d]].\n");
+        write(&src, "d.md", "# D\n\nOn its own.\n");
+
+        let config = Config {
+            source: src.display().to_string(),
+            destination: dir.join("out").display().to_string(),
+            theme: concat!(env!("CARGO_MANIFEST_DIR"), "/examples/theme").to_string(),
+            markdown: config::MarkdownOptions {
+                cache: Some(crate::cache::Cache::new(dir.join("cache"))),
+                ..config::MarkdownOptions::default()
+            },
+            images: images::ImageOptions::default(),
+            base_url: None,
+            feeds: feeds::FeedOptions::default(),
+            robots: sitemap::RobotsOptions::default(),
+            tag_sort: content::SortKey::Title,
+            section_sort: content::SortKey::Title,
+            per_page: 0,
+            site: config::Site::default(),
+            full_rebuild: false,
+            collisions: filesystem::Collisions::Error,
+        };
+        generate_site(&config).unwrap();
+
+        // Pages that aren't rendered again keep this marker.
+        let out = dir.join("out");
+        for page in &["a.html", "b.html", "c.html", "d.html"] {
+            write(&out, page, "stale");
+        }
+        write(
+            &src,
+            "b.md",
+            "# B\n\nFirst paragraph.\n\nSecond paragraph, now longer.\n",
+        );
+        generate_site(&config).unwrap();
+
+        let stale = |page: &str| fs::read_to_string(out.join(page)).unwrap() == "stale";
+        assert!(!stale("a.html"));
+        assert!(!stale("b.html"));
+        assert!(stale("c.html"));
+        assert!(stale("d.html"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,105 @@
+/// Marks
+///
+/// Obsidian style highlights, `==like this==`, rendered as `<mark>`. Since
+/// highlights may wrap other inline markup, e.g. `==some *emphasis*==`, the
+/// delimiters are matched across the text nodes sharing a parent, and each
+/// one is swapped for a raw `<mark>` or `</mark>` tag.
+use crate::content::new_node;
+use comrak::nodes::{AstNode, NodeValue};
+use comrak::Arena;
+
+/// Replaces every matched pair of `==` delimiters in the document.
+pub fn transform<'a>(arena: &'a Arena<AstNode<'a>>, root: &'a AstNode<'a>) {
+    let parents: Vec<&'a AstNode<'a>> = root
+        .descendants()
+        .filter(|node| node.children().any(is_text))
+        .collect();
+    for parent in parents {
+        transform_children(arena, parent);
+    }
+}
+
+fn is_text<'a>(node: &'a AstNode<'a>) -> bool {
+    matches!(node.data.borrow().value, NodeValue::Text(_))
+}
+
+fn transform_children<'a>(arena: &'a Arena<AstNode<'a>>, parent: &'a AstNode<'a>) {
+    // Find all candidate delimiters, as (node, byte offset, can open, can close).
+    let mut delimiters = vec![];
+    for node in parent.children() {
+        if let NodeValue::Text(ref literal) = node.data.borrow().value {
+            for (offset, can_open, can_close) in find_delimiters(literal) {
+                delimiters.push((node, offset, can_open, can_close));
+            }
+        }
+    }
+
+    // Pair them up, left to right.
+    let mut tags: Vec<(&'a AstNode<'a>, usize, &str)> = vec![];
+    let mut opener = None;
+    for (node, offset, can_open, can_close) in delimiters {
+        match opener {
+            Some(open) if can_close => {
+                tags.push(open);
+                tags.push((node, offset, "</mark>"));
+                opener = None;
+            }
+            _ if can_open => opener = Some((node, offset, "<mark>")),
+            _ => {}
+        }
+    }
+
+    // Split the text nodes around their tags, back to front so that offsets
+    // stay valid.
+    for (node, offset, tag) in tags.into_iter().rev() {
+        let literal = match node.data.borrow().value {
+            NodeValue::Text(ref literal) => literal.clone(),
+            _ => continue,
+        };
+        let after = new_node(arena, NodeValue::Text(literal[offset + 2..].to_vec()));
+        let html = new_node(arena, NodeValue::HtmlInline(tag.as_bytes().to_vec()));
+        node.insert_after(after);
+        node.insert_after(html);
+        node.data.borrow_mut().value = NodeValue::Text(literal[..offset].to_vec());
+    }
+}
+
+// Finds runs of exactly two `=`. A delimiter can open a highlight when it's
+// followed by non-whitespace, and close one when it's preceded by it.
+fn find_delimiters(literal: &[u8]) -> Vec<(usize, bool, bool)> {
+    let mut delimiters = vec![];
+    let mut i = 0;
+    while i < literal.len() {
+        if literal[i] != b'=' {
+            i += 1;
+            continue;
+        }
+        let run = literal[i..].iter().take_while(|&&b| b == b'=').count();
+        if run == 2 {
+            let before = i.checked_sub(1).map(|j| literal[j]);
+            let after = literal.get(i + 2).copied();
+            let can_open = !after.is_some_and(|b| b.is_ascii_whitespace());
+            let can_close = !before.is_some_and(|b| b.is_ascii_whitespace());
+            delimiters.push((i, can_open, can_close));
+        }
+        i += run;
+    }
+    delimiters
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::MarkdownOptions;
+    use crate::content::parse_raw;
+
+    #[test]
+    fn transform_marks_highlights() {
+        let raw = "Some ==highlighted *text*== and `==code==`, a == b, x===y.";
+        let content = parse_raw(raw, &MarkdownOptions::default()).unwrap();
+
+        assert_eq!(
+            content.body,
+            "<p>Some <mark>highlighted <em>text</em></mark> and <code>==code==</code>, a == b, x===y.</p>\n"
+        );
+    }
+}
@@ -0,0 +1,234 @@
+/// Math
+///
+/// Build-time rendering of `$...$` (inline) and `$$...$$` (display) LaTeX
+/// into MathML.
+///
+/// Math has to be pulled out before the Markdown is parsed, otherwise
+/// things like `a_1 * b_2` end up as emphasis. Each formula is swapped for a
+/// placeholder, and the placeholders are swapped for the rendered MathML once
+/// the document has been parsed.
+use crate::content::{new_node, replace_with_html};
+use crate::scan;
+use comrak::nodes::{AstNode, NodeValue};
+use comrak::Arena;
+use latex2mathml::{latex_to_mathml, DisplayStyle};
+
+// Private use characters, which won't show up in actual notes.
+const OPEN: char = '\u{E000}';
+const CLOSE: char = '\u{E001}';
+
+#[derive(Debug)]
+pub struct Formula {
+    display: bool,
+    html: String,
+}
+
+/// Replaces all math outside of code with placeholders, returning the
+/// rewritten Markdown along with the rendered formulas.
+pub fn extract(raw: &str) -> (String, Vec<Formula>) {
+    let mut formulas = vec![];
+    let markdown = scan::map_prose(raw, |text| replace_math(text, &mut formulas));
+    (markdown, formulas)
+}
+
+/// Swaps the placeholders left by `extract` for their rendered formulas.
+pub fn restore<'a>(arena: &'a Arena<AstNode<'a>>, root: &'a AstNode<'a>, formulas: &[Formula]) {
+    if formulas.is_empty() {
+        return;
+    }
+    let nodes: Vec<&'a AstNode<'a>> = root.descendants().collect();
+    for node in nodes {
+        let text = match node.data.borrow().value {
+            NodeValue::Text(ref literal) => String::from_utf8_lossy(literal).to_string(),
+            _ => continue,
+        };
+        if !text.contains(OPEN) {
+            continue;
+        }
+
+        // A display formula on its own replaces the paragraph around it.
+        if let Some(parent) = node.parent() {
+            let alone = node.previous_sibling().is_none() && node.next_sibling().is_none();
+            let is_paragraph = matches!(parent.data.borrow().value, NodeValue::Paragraph);
+            if let (true, true, [Piece::Formula(i)]) = (alone, is_paragraph, &pieces(&text)[..]) {
+                if let Some(formula) = formulas.get(*i).filter(|f| f.display) {
+                    replace_with_html(parent, format!("{}\n", formula.html));
+                    continue;
+                }
+            }
+        }
+
+        for piece in pieces(&text) {
+            let value = match piece {
+                Piece::Text(t) => NodeValue::Text(t.into_bytes()),
+                Piece::Formula(i) => match formulas.get(i) {
+                    Some(formula) => NodeValue::HtmlInline(formula.html.clone().into_bytes()),
+                    None => continue,
+                },
+            };
+            node.insert_before(new_node(arena, value));
+        }
+        node.detach();
+    }
+}
+
+fn placeholder(index: usize) -> String {
+    format!("{}{}{}", OPEN, index, CLOSE)
+}
+
+#[derive(Debug, PartialEq)]
+enum Piece {
+    Text(String),
+    Formula(usize),
+}
+
+fn pieces(text: &str) -> Vec<Piece> {
+    let mut pieces = vec![];
+    let mut parts = text.split(OPEN);
+    if let Some(first) = parts.next().filter(|t| !t.is_empty()) {
+        pieces.push(Piece::Text(first.to_string()));
+    }
+    for part in parts {
+        let (index, rest) = part.split_once(CLOSE).unwrap_or(("", part));
+        if let Ok(i) = index.parse() {
+            pieces.push(Piece::Formula(i));
+        }
+        if !rest.is_empty() {
+            pieces.push(Piece::Text(rest.to_string()));
+        }
+    }
+    pieces
+}
+
+fn replace_math(text: &str, formulas: &mut Vec<Formula>) -> String {
+    let bytes = text.as_bytes();
+    let mut out = String::with_capacity(text.len());
+    let mut last = 0;
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' => i += 2,
+            b'$' => match find_math(text, i) {
+                Some((end, latex, display)) => {
+                    out.push_str(&text[last..i]);
+                    out.push_str(&placeholder(formulas.len()));
+                    formulas.push(render(latex, display));
+                    last = end;
+                    i = end;
+                }
+                None => i += bytes[i..].iter().take_while(|&&b| b == b'$').count(),
+            },
+            _ => i += 1,
+        }
+    }
+
+    out.push_str(&text[last..]);
+    out
+}
+
+// Finds the math starting at `start`, returning its end offset, its LaTeX
+// source and whether it's display math. Inline math follows the same rules
+// as Pandoc, so that "$5 and $10" is left alone: the opening `$` must be
+// followed by a non-space, and the closing `$` must be preceded by a
+// non-space and not followed by a digit.
+fn find_math(text: &str, start: usize) -> Option<(usize, &str, bool)> {
+    if text[start..].starts_with("$$") {
+        let body = start + 2;
+        let end = body + text[body..].find("$$")?;
+        let latex = text[body..end].trim();
+        if latex.is_empty() {
+            return None;
+        }
+        return Some((end + 2, latex, true));
+    }
+
+    let body = start + 1;
+    if text[body..].chars().next()?.is_whitespace() {
+        return None;
+    }
+
+    let bytes = text.as_bytes();
+    let mut i = body;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' => i += 2,
+            // Inline math doesn't span paragraphs.
+            b'\n'
+                if text[i + 1..]
+                    .trim_start_matches([' ', '\t'])
+                    .starts_with('\n') =>
+            {
+                return None
+            }
+            b'$' => {
+                let after_space = text[..i].ends_with(char::is_whitespace);
+                let before_digit = text[i + 1..].starts_with(|c: char| c.is_ascii_digit());
+                if !after_space && !before_digit {
+                    return Some((i + 1, &text[body..i], false));
+                }
+                i += 1;
+            }
+            _ => i += 1,
+        }
+    }
+    None
+}
+
+fn render(latex: &str, display: bool) -> Formula {
+    let style = if display {
+        DisplayStyle::Block
+    } else {
+        DisplayStyle::Inline
+    };
+    let html = latex_to_mathml(latex, style).unwrap_or_else(|e| {
+        format!(
+            "<code class=\"math-error\" title=\"{}\">{}</code>",
+            tera::escape_html(&e.to_string()),
+            tera::escape_html(latex)
+        )
+    });
+    Formula { display, html }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extract_finds_inline_and_display_math() {
+        let (markdown, formulas) = extract("Let $a_1$ be\n\n$$\nx^2\n$$\n");
+
+        assert_eq!(
+            markdown,
+            format!("Let {} be\n\n{}\n", placeholder(0), placeholder(1))
+        );
+        assert!(!formulas[0].display);
+        assert!(formulas[1].display);
+        assert!(formulas[1].html.contains("display=\"block\""));
+    }
+
+    #[test]
+    fn extract_ignores_prices_and_code() {
+        let raw = "It costs $5 and $10, or `$x$`, not \\$y$.";
+        let (markdown, formulas) = extract(raw);
+
+        assert_eq!(markdown, raw);
+        assert!(formulas.is_empty());
+    }
+
+    #[test]
+    fn pieces_splits_placeholders() {
+        let text = format!("a {} b{}", placeholder(0), placeholder(12));
+
+        assert_eq!(
+            pieces(&text),
+            vec![
+                Piece::Text("a ".to_string()),
+                Piece::Formula(0),
+                Piece::Text(" b".to_string()),
+                Piece::Formula(12),
+            ]
+        );
+    }
+}
@@ -0,0 +1,209 @@
+/// Output
+///
+/// Keeps track of the files a build produces, in a manifest in the
+/// destination folder, so that the next build can remove the files it no
+/// longer produces, e.g. the page of a deleted or renamed note. Only files
+/// listed in the manifest are ever removed.
+use crate::error::Error;
+use crate::filesystem::Filesystem;
+use crate::traits::Writer;
+use std::collections::BTreeSet;
+use std::fs;
+use std::io::Write;
+use std::path::{Component, Path};
+use std::sync::Mutex;
+
+/// The name of the manifest in the destination folder.
+pub const MANIFEST: &str = ".spade-manifest";
+
+/// A writer recording the files written through it.
+pub struct Output {
+    inner: Filesystem,
+    files: Mutex<BTreeSet<String>>,
+}
+
+// Permalinks may or may not start with a slash, or contain doubled ones.
+fn normalize(permalink: &str) -> String {
+    permalink
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+impl Output {
+    pub fn new(inner: Filesystem) -> Self {
+        Output {
+            inner,
+            files: Mutex::new(BTreeSet::new()),
+        }
+    }
+
+    /// Records a file as produced by this build without writing it, for files
+    /// left as they were since the previous build.
+    pub fn keep(&self, permalink: &str) {
+        self.files.lock().unwrap().insert(normalize(permalink));
+    }
+
+    pub fn exists(&self, permalink: &str) -> bool {
+        self.inner.exists(permalink)
+    }
+
+    /// The files produced by this build.
+    pub fn files(&self) -> BTreeSet<String> {
+        self.files.lock().unwrap().clone()
+    }
+}
+
+impl Writer for Output {
+    fn get_writer(&self, permalink: &str) -> std::io::Result<Box<dyn Write>> {
+        self.keep(permalink);
+        self.inner.get_writer(permalink)
+    }
+}
+
+/// The files listed in the manifest of `destination`, if it has one.
+pub fn load(destination: &Path) -> Option<BTreeSet<String>> {
+    let text = fs::read_to_string(destination.join(MANIFEST)).ok()?;
+    Some(text.lines().map(str::to_string).collect())
+}
+
+pub fn save(destination: &Path, files: &BTreeSet<String>) -> Result<(), Error> {
+    let text: String = files.iter().map(|file| format!("{}\n", file)).collect();
+    fs::write(destination.join(MANIFEST), text)?;
+    Ok(())
+}
+
+/// Removes the files of the previous build that the current one didn't
+/// produce, along with the folders they leave empty. Returns the number of
+/// files removed.
+pub fn remove_stale(
+    destination: &Path,
+    previous: &BTreeSet<String>,
+    current: &BTreeSet<String>,
+) -> Result<usize, Error> {
+    let mut removed = 0;
+    for file in previous.difference(current) {
+        // Never step outside the destination, whatever the manifest says:
+        // joining an absolute path would replace the destination, and `..`
+        // would climb out of it.
+        let relative = Path::new(file);
+        if relative.is_absolute()
+            || relative
+                .components()
+                .any(|c| !matches!(c, Component::Normal(_)))
+        {
+            continue;
+        }
+        let path = destination.join(file);
+        if path.is_file() {
+            fs::remove_file(&path)?;
+            removed += 1;
+        }
+        remove_empty_parents(destination, &path);
+    }
+    Ok(removed)
+}
+
+fn remove_empty_parents(destination: &Path, path: &Path) {
+    let mut parent = path.parent();
+    while let Some(dir) = parent {
+        if dir == destination || fs::remove_dir(dir).is_err() {
+            break;
+        }
+        parent = dir.parent();
+    }
+}
+
+// All files below `dir`, relative to `root`.
+fn list(root: &Path, dir: &Path, files: &mut Vec<String>) -> Result<(), Error> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            list(root, &path, files)?;
+        } else if let Ok(relative) = path.strip_prefix(root) {
+            files.push(relative.to_string_lossy().replace('\\', "/"));
+        }
+    }
+    Ok(())
+}
+
+/// Empties `destination` before a build, refusing to if it holds any file a
+/// build didn't produce, so that pointing Spade at the wrong folder doesn't
+/// cost anyone their files.
+pub fn clean(destination: &Path) -> Result<usize, Error> {
+    if !destination.exists() {
+        return Ok(0);
+    }
+    let mut files = vec![];
+    list(destination, destination, &mut files)?;
+    files.retain(|file| file != MANIFEST);
+    if files.is_empty() {
+        return Ok(0);
+    }
+
+    let produced = load(destination).unwrap_or_default();
+    let foreign: Vec<&String> = files.iter().filter(|f| !produced.contains(*f)).collect();
+    if let Some(file) = foreign.first() {
+        return Err(Error::Output(format!(
+            "refusing to clean {}, since Spade didn't create {}{}",
+            destination.display(),
+            file,
+            match foreign.len() {
+                1 => String::new(),
+                n => format!(" and {} other files", n - 1),
+            }
+        )));
+    }
+
+    let removed = remove_stale(destination, &files.into_iter().collect(), &BTreeSet::new())?;
+    fs::remove_file(destination.join(MANIFEST))?;
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+    use std::fs;
+
+    fn set(files: &[&str]) -> BTreeSet<String> {
+        files.iter().map(|f| f.to_string()).collect()
+    }
+
+    #[test]
+    fn remove_stale_keeps_current_files() {
+        let dir = std::env::temp_dir().join(format!("spade-output-{}", std::process::id()));
+        fs::create_dir_all(dir.join("old")).unwrap();
+        for file in ["kept.html", "gone.html", "old/page.html", "mine.txt"] {
+            fs::write(dir.join(file), "").unwrap();
+        }
+
+        // Files outside the destination are never removed.
+        let outside = std::env::temp_dir().join(format!("spade-outside-{}", std::process::id()));
+        fs::write(&outside, "").unwrap();
+        let escape = format!("../{}", outside.file_name().unwrap().to_str().unwrap());
+
+        let previous = set(&[
+            "kept.html",
+            "gone.html",
+            "old/page.html",
+            outside.to_str().unwrap(),
+            &escape,
+        ]);
+        let removed = super::remove_stale(&dir, &previous, &set(&["kept.html"])).unwrap();
+        assert_eq!(removed, 2);
+        assert!(outside.exists());
+        fs::remove_file(&outside).unwrap();
+        assert!(dir.join("kept.html").exists());
+        assert!(!dir.join("old").exists());
+
+        // mine.txt wasn't created by a build.
+        super::save(&dir, &set(&["kept.html"])).unwrap();
+        assert!(super::clean(&dir).is_err());
+        fs::remove_file(dir.join("mine.txt")).unwrap();
+        assert_eq!(super::clean(&dir).unwrap(), 1);
+        assert!(!dir.join(super::MANIFEST).exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
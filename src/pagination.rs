@@ -0,0 +1,95 @@
+/// Pagination
+///
+/// Splits long listings into pages. The first page lives at the listing's
+/// own URL, following pages at `{url}/page/{n}`, e.g. `/tags/rust/page/2`.
+use serde::Serialize;
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Paginator {
+    /// The current page, counting from 1.
+    pub current: usize,
+    /// The number of pages.
+    pub total: usize,
+    pub per_page: usize,
+    /// The number of items across all pages.
+    pub total_items: usize,
+    pub first: String,
+    pub last: String,
+    pub previous: Option<String>,
+    pub next: Option<String>,
+}
+
+/// The URL of a page of the listing at `base`.
+pub fn page_url(base: &str, page: usize) -> String {
+    match page {
+        1 => base.to_string(),
+        n => format!("{}/page/{}", base.trim_end_matches('/'), n),
+    }
+}
+
+/// The number of pages `total_items` items take up.
+pub fn count(total_items: usize, per_page: usize) -> usize {
+    match per_page {
+        0 => 1,
+        n => total_items.div_ceil(n).max(1),
+    }
+}
+
+/// Splits items into pages of `per_page` items each, or a single page if
+/// `per_page` is 0. There's always at least one page, even without items.
+pub fn paginate<'a, T>(items: &'a [T], per_page: usize, base: &str) -> Vec<(Paginator, &'a [T])> {
+    let chunks: Vec<&[T]> = match per_page {
+        0 => vec![items],
+        _ if items.is_empty() => vec![items],
+        n => items.chunks(n).collect(),
+    };
+    let total = chunks.len();
+
+    chunks
+        .into_iter()
+        .enumerate()
+        .map(|(i, chunk)| {
+            let current = i + 1;
+            let paginator = Paginator {
+                current,
+                total,
+                per_page,
+                total_items: items.len(),
+                first: page_url(base, 1),
+                last: page_url(base, total),
+                previous: (current > 1).then(|| page_url(base, current - 1)),
+                next: (current < total).then(|| page_url(base, current + 1)),
+            };
+            (paginator, chunk)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn paginate_links_pages() {
+        let items = [1, 2, 3, 4, 5];
+        let pages = super::paginate(&items, 2, "/tags/rust");
+
+        assert_eq!(pages.len(), 3);
+        assert_eq!(super::count(items.len(), 2), 3);
+        assert_eq!(pages[0].1, &[1, 2]);
+        assert_eq!(pages[0].0.previous, None);
+        assert_eq!(pages[0].0.next.as_deref(), Some("/tags/rust/page/2"));
+        assert_eq!(pages[1].0.previous.as_deref(), Some("/tags/rust"));
+        assert_eq!(pages[2].1, &[5]);
+        assert_eq!(pages[2].0.last, "/tags/rust/page/3");
+        assert_eq!(pages[2].0.next, None);
+    }
+
+    #[test]
+    fn paginate_without_limit_is_one_page() {
+        let items: [u8; 0] = [];
+        let pages = super::paginate(&items, 0, "/journal");
+
+        assert_eq!(pages.len(), 1);
+        assert_eq!(pages[0].0.total, 1);
+        assert_eq!(super::count(0, 2), 1);
+    }
+}
@@ -0,0 +1,169 @@
+/// Scan
+///
+/// A light pass over raw Markdown that tells prose apart from code, for the
+/// transforms that have to run before comrak gets to parse the document.
+/// It recognizes fenced code blocks and inline code spans; indented code
+/// blocks are treated as prose.
+
+#[derive(Debug, PartialEq)]
+pub enum Segment<'a> {
+    Prose(&'a str),
+    Code(&'a str),
+}
+
+/// Splits raw Markdown into consecutive prose and code segments.
+pub fn segments(raw: &str) -> Vec<Segment<'_>> {
+    let mut segments = vec![];
+    let mut prose_start = 0;
+    let mut fence: Option<(char, usize, usize)> = None;
+    let mut offset = 0;
+
+    for line in raw.split_inclusive('\n') {
+        let line_start = offset;
+        offset += line.len();
+
+        match fence {
+            Some((c, len, start)) => {
+                if closes_fence(line, c, len) {
+                    segments.push(Segment::Code(&raw[start..offset]));
+                    prose_start = offset;
+                    fence = None;
+                }
+            }
+            None => {
+                if let Some((c, len)) = opens_fence(line) {
+                    split_code_spans(&raw[prose_start..line_start], &mut segments);
+                    fence = Some((c, len, line_start));
+                }
+            }
+        }
+    }
+
+    match fence {
+        Some((_, _, start)) => segments.push(Segment::Code(&raw[start..])),
+        None => split_code_spans(&raw[prose_start..], &mut segments),
+    }
+
+    segments
+}
+
+/// Runs `f` over every prose segment, leaving code untouched.
+pub fn map_prose<F: FnMut(&str) -> String>(raw: &str, mut f: F) -> String {
+    segments(raw)
+        .into_iter()
+        .map(|segment| match segment {
+            Segment::Prose(text) => f(text),
+            Segment::Code(code) => code.to_string(),
+        })
+        .collect()
+}
+
+// Fences may be nested in blockquotes and lists, so we skip past any
+// leading indentation and blockquote markers.
+fn fence_body(line: &str) -> &str {
+    line.trim_start_matches([' ', '\t', '>'])
+}
+
+fn opens_fence(line: &str) -> Option<(char, usize)> {
+    let body = fence_body(line);
+    let c = body.chars().next()?;
+    if c != '`' && c != '~' {
+        return None;
+    }
+    let len = body.chars().take_while(|&ch| ch == c).count();
+    // Backtick fences can't have backticks in their info string.
+    if len < 3 || (c == '`' && body[len..].contains('`')) {
+        return None;
+    }
+    Some((c, len))
+}
+
+fn closes_fence(line: &str, c: char, len: usize) -> bool {
+    let body = fence_body(line).trim_end();
+    body.len() >= len && body.chars().all(|ch| ch == c)
+}
+
+fn split_code_spans<'a>(text: &'a str, segments: &mut Vec<Segment<'a>>) {
+    let bytes = text.as_bytes();
+    let mut prose_start = 0;
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if bytes[i] == b'\\' {
+            i += 2;
+            continue;
+        }
+        if bytes[i] != b'`' {
+            i += 1;
+            continue;
+        }
+        let run = backtick_run(bytes, i);
+        match find_closing_run(bytes, i + run, run) {
+            Some(end) => {
+                if prose_start < i {
+                    segments.push(Segment::Prose(&text[prose_start..i]));
+                }
+                segments.push(Segment::Code(&text[i..end]));
+                prose_start = end;
+                i = end;
+            }
+            None => i += run,
+        }
+    }
+
+    if prose_start < text.len() {
+        segments.push(Segment::Prose(&text[prose_start..]));
+    }
+}
+
+fn backtick_run(bytes: &[u8], start: usize) -> usize {
+    bytes[start..].iter().take_while(|&&b| b == b'`').count()
+}
+
+// Returns the end offset of a backtick run of exactly `len` backticks.
+fn find_closing_run(bytes: &[u8], from: usize, len: usize) -> Option<usize> {
+    let mut i = from;
+    while i < bytes.len() {
+        if bytes[i] == b'`' {
+            let run = backtick_run(bytes, i);
+            if run == len {
+                return Some(i + run);
+            }
+            i += run;
+        } else {
+            i += 1;
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Segment::{Code, Prose};
+
+    #[test]
+    fn segments_handles_fences_and_spans() {
+        let raw = "Some `code` here\n```rust\nlet a = `b`;\n```\nThe end";
+
+        assert_eq!(
+            super::segments(raw),
+            vec![
+                Prose("Some "),
+                Code("`code`"),
+                Prose(" here\n"),
+                Code("```rust\nlet a = `b`;\n```\n"),
+                Prose("The end"),
+            ]
+        );
+    }
+
+    #[test]
+    fn segments_handles_unclosed_fences_and_spans() {
+        let raw = "A lone ` backtick\n~~~\nnever closed";
+
+        assert_eq!(
+            super::segments(raw),
+            vec![Prose("A lone ` backtick\n"), Code("~~~\nnever closed")]
+        );
+    }
+}
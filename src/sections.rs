@@ -0,0 +1,204 @@
+/// Sections
+///
+/// Every folder in our source folder is a section, with a generated listing
+/// page at `{folder}/index.html` linking its articles and subfolders. A
+/// folder's `index.md` (or `_index.md`) isn't published on its own, but
+/// passed along to the listing page, so it can introduce the section and set
+/// its sort order.
+///
+/// The `tags` folder is left out, since that's where the tag pages live.
+use crate::content::{sort_summaries, Article, ArticleSummary, Content, SortKey};
+use crate::filesystem::permalink_from_path;
+use crate::pagination;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+
+const INDEX_NAMES: &[&str] = &["index", "_index"];
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SectionLink {
+    pub name: String,
+    pub permalink: String,
+    /// Number of articles in the section and all of its subsections.
+    pub count: usize,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Section {
+    /// The folder of the section, e.g. `journal/2021`.
+    pub path: String,
+    pub name: String,
+    pub permalink: String,
+    pub parent: Option<SectionLink>,
+    pub subsections: Vec<SectionLink>,
+    pub articles: Vec<ArticleSummary>,
+    /// The id of the section's index note, if it has one.
+    #[serde(skip)]
+    pub index: Option<String>,
+}
+
+// The position of an index note's name in `INDEX_NAMES`, if it's one.
+fn index_rank(id: &str) -> Option<usize> {
+    let name = id.rsplit('/').next().unwrap_or("");
+    match id.contains('/') {
+        true => INDEX_NAMES.iter().position(|index| *index == name),
+        false => None,
+    }
+}
+
+/// Whether an article is the index note of its folder.
+pub fn is_index(article: &Article) -> bool {
+    index_rank(&article.id).is_some()
+}
+
+/// Whether a note is an index note of one of `sections`, and so not
+/// published on its own. That goes for both `index.md` and `_index.md`, even
+/// though only one of them introduces the section.
+pub fn is_section_index(sections: &BTreeMap<String, Section>, id: &str) -> bool {
+    index_rank(id).is_some() && folder(id).is_some_and(|folder| sections.contains_key(folder))
+}
+
+fn folder(id: &str) -> Option<&str> {
+    id.rsplit_once('/').map(|(folder, _)| folder)
+}
+
+fn link(path: &str, count: usize) -> SectionLink {
+    SectionLink {
+        name: path.rsplit('/').next().unwrap_or(path).to_string(),
+        permalink: permalink_from_path(Path::new(path)),
+        count,
+    }
+}
+
+/// The file a page of the section at `permalink` is written to.
+pub fn page_path(permalink: &str, page: usize) -> String {
+    match page {
+        1 => format!("{}/index.html", permalink),
+        n => format!("{}.html", pagination::page_url(permalink, n)),
+    }
+}
+
+/// Builds all sections, keyed by folder.
+pub fn build(
+    contents: &HashMap<String, Content>,
+    default_sort: SortKey,
+) -> BTreeMap<String, Section> {
+    let articles = contents.values().filter_map(|c| match c {
+        Content::Article(a) if a.id.split('/').next() != Some("tags") => Some(a),
+        _ => None,
+    });
+
+    // Every folder with articles anywhere below it gets a section, so we
+    // count the articles of each folder and all of its ancestors.
+    let mut counts: BTreeMap<String, usize> = BTreeMap::new();
+    let mut direct: HashMap<String, Vec<&Article>> = HashMap::new();
+    for article in articles {
+        let mut current = folder(&article.id);
+        if let Some(folder) = current {
+            direct.entry(folder.to_string()).or_default().push(article);
+        }
+        if is_index(article) {
+            continue;
+        }
+        while let Some(folder) = current {
+            *counts.entry(folder.to_string()).or_default() += 1;
+            current = self::folder(folder);
+        }
+    }
+
+    counts
+        .iter()
+        .map(|(path, &count)| {
+            let notes = direct.get(path).map(Vec::as_slice).unwrap_or(&[]);
+            // `index.md` wins over `_index.md`.
+            let mut indexes: Vec<&&Article> = notes.iter().filter(|a| is_index(a)).collect();
+            indexes.sort_by_key(|a| index_rank(&a.id));
+            if let [index, ignored, ..] = indexes[..] {
+                eprintln!(
+                    "Section {} has more than one index note, using {} rather than {}",
+                    path, index.src, ignored.src
+                );
+            }
+            let index = indexes.first();
+            let sort = index
+                .and_then(|a| a.meta.as_ref())
+                .and_then(|m| m.sort.as_ref())
+                .and_then(|sort| match sort.parse() {
+                    Ok(sort) => Some(sort),
+                    Err(e) => {
+                        eprintln!("Ignoring the sort order of section {}: {}", path, e);
+                        None
+                    }
+                })
+                .unwrap_or(default_sort);
+
+            let mut articles: Vec<ArticleSummary> = notes
+                .iter()
+                .filter(|a| !is_index(a))
+                .map(|a| a.summary())
+                .collect();
+            sort_summaries(&mut articles, sort);
+
+            let own = link(path, count);
+            let section = Section {
+                path: path.clone(),
+                name: own.name,
+                permalink: own.permalink,
+                parent: folder(path).map(|parent| link(parent, counts[parent])),
+                subsections: counts
+                    .iter()
+                    .filter(|(sub, _)| folder(sub) == Some(path))
+                    .map(|(sub, &count)| link(sub, count))
+                    .collect(),
+                articles,
+                index: index.map(|a| a.id.clone()),
+            };
+            (path.clone(), section)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::content::{Article, Content, SortKey};
+    use std::collections::HashMap;
+
+    #[test]
+    fn build_nests_sections() {
+        let mut contents = HashMap::new();
+        for id in [
+            "top",
+            "journal/_index",
+            "journal/index",
+            "journal/2021/day one",
+            "journal/b",
+            "tags/rust",
+        ] {
+            let article = Article {
+                id: id.to_string(),
+                permalink: format!("/{}", id),
+                src: format!("{}.md", id),
+                meta: None,
+                content: None,
+                raw: String::new(),
+            };
+            contents.insert(id.to_string(), Content::Article(Box::new(article)));
+        }
+
+        let sections = super::build(&contents, SortKey::Title);
+        assert_eq!(
+            sections.keys().collect::<Vec<_>>(),
+            vec!["journal", "journal/2021"]
+        );
+
+        let journal = &sections["journal"];
+        assert_eq!(journal.index.as_deref(), Some("journal/index"));
+        assert!(super::is_section_index(&sections, "journal/_index"));
+        assert!(!super::is_section_index(&sections, "journal/b"));
+        assert_eq!(journal.articles.len(), 1);
+        assert_eq!(journal.subsections[0].permalink, "/journal/2021");
+        assert_eq!(journal.subsections[0].count, 1);
+        assert_eq!(sections["journal/2021"].parent.as_ref().unwrap().count, 2);
+    }
+}
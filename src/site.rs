@@ -0,0 +1,142 @@
+/// Site
+///
+/// Everything templates get to know about the site as a whole, computed once
+/// per build and passed to every template as `site`, next to the values from
+/// the config file. Meant for navigation, "recent notes" sidebars and the
+/// like.
+use crate::config;
+use crate::content::{sort_summaries, Article, ArticleSummary, SortKey};
+use crate::sections::Section;
+use crate::tags::{TagGroup, TagNode};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+#[derive(Debug, Serialize)]
+pub struct Site<'a> {
+    #[serde(flatten)]
+    pub config: &'a config::Site,
+    /// All published articles, most recently updated first.
+    pub articles: Vec<ArticleSummary>,
+    /// Tag groups by slug.
+    pub tags: &'a BTreeMap<String, TagGroup>,
+    pub tag_tree: &'a [TagNode],
+    /// Sections by folder, for themes that render them.
+    pub sections: &'a BTreeMap<String, Section>,
+    pub graph: Graph,
+}
+
+/// The links between published articles.
+#[derive(Debug, Default, Serialize)]
+pub struct Graph {
+    pub nodes: Vec<GraphNode>,
+    pub edges: Vec<GraphEdge>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct GraphNode {
+    pub id: String,
+    pub title: String,
+    pub permalink: String,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct GraphEdge {
+    pub source: String,
+    pub target: String,
+}
+
+impl Graph {
+    /// The graph of the given articles, with the links between them from
+    /// `links`, the ids of the articles each article links to. Links to
+    /// anything else are left out.
+    pub fn new(articles: &[&Article], links: &HashMap<String, Vec<String>>) -> Self {
+        let mut nodes: Vec<GraphNode> = articles
+            .iter()
+            .map(|a| GraphNode {
+                id: a.id.clone(),
+                title: a.title(),
+                permalink: a.permalink.clone(),
+            })
+            .collect();
+        nodes.sort_by(|a, b| a.id.cmp(&b.id));
+
+        let published: HashSet<&str> = nodes.iter().map(|node| node.id.as_str()).collect();
+        let mut edges: Vec<GraphEdge> = links
+            .iter()
+            .filter(|(source, _)| published.contains(source.as_str()))
+            .flat_map(|(source, targets)| {
+                targets
+                    .iter()
+                    .filter(|target| published.contains(target.as_str()))
+                    .map(move |target| GraphEdge {
+                        source: source.clone(),
+                        target: target.clone(),
+                    })
+            })
+            .collect();
+        edges.sort_by(|a, b| (&a.source, &a.target).cmp(&(&b.source, &b.target)));
+        edges.dedup();
+
+        Graph { nodes, edges }
+    }
+}
+
+impl<'a> Site<'a> {
+    pub fn new(
+        config: &'a config::Site,
+        articles: &[&Article],
+        links: &HashMap<String, Vec<String>>,
+        tags: &'a BTreeMap<String, TagGroup>,
+        tag_tree: &'a [TagNode],
+        sections: &'a BTreeMap<String, Section>,
+    ) -> Self {
+        let mut summaries: Vec<ArticleSummary> = articles.iter().map(|a| a.summary()).collect();
+        sort_summaries(&mut summaries, SortKey::Updated);
+        Site {
+            config,
+            articles: summaries,
+            tags,
+            tag_tree,
+            sections,
+            graph: Graph::new(articles, links),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Graph, GraphEdge};
+    use crate::content::Article;
+    use std::collections::HashMap;
+
+    #[test]
+    fn graph_links_published_articles() {
+        let articles: Vec<Article> = ["a", "b"]
+            .iter()
+            .map(|id| Article {
+                id: id.to_string(),
+                permalink: format!("/{}", id),
+                src: format!("{}.md", id),
+                meta: None,
+                content: None,
+                raw: String::new(),
+            })
+            .collect();
+        let mut links = HashMap::new();
+        links.insert(
+            "a".to_string(),
+            vec!["b".to_string(), "b".to_string(), "draft".to_string()],
+        );
+        links.insert("draft".to_string(), vec!["a".to_string()]);
+
+        let graph = Graph::new(&articles.iter().collect::<Vec<_>>(), &links);
+        assert_eq!(graph.nodes.len(), 2);
+        assert_eq!(
+            graph.edges,
+            vec![GraphEdge {
+                source: "a".to_string(),
+                target: "b".to_string(),
+            }]
+        );
+    }
+}
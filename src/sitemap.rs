@@ -0,0 +1,141 @@
+/// Sitemap
+///
+/// A `sitemap.xml` of every published page, so that search engines find
+/// notes nothing links to, and a `robots.txt` pointing at it. Sitemaps need
+/// absolute URLs, so like feeds they're only generated when a base URL is
+/// configured. Drafts and the 404 page are left out.
+use crate::content::{Article, ArticleSummary};
+use crate::feeds::escape;
+use chrono::{DateTime, Utc};
+
+#[derive(Clone, Debug, Default)]
+pub struct RobotsOptions {
+    /// Paths crawlers are asked to stay out of, e.g. `/private`.
+    pub disallow: Vec<String>,
+}
+
+/// A page listed in the sitemap. Pages written to an `index.html` are listed
+/// with a trailing slash, e.g. `/journal/`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Entry {
+    pub permalink: String,
+    pub lastmod: Option<DateTime<Utc>>,
+}
+
+impl Entry {
+    pub fn new(permalink: &str, lastmod: Option<DateTime<Utc>>) -> Self {
+        // The root index note is served at `/`.
+        let permalink = match permalink {
+            "/index" => "/",
+            permalink => permalink,
+        };
+        Entry {
+            permalink: permalink.to_string(),
+            lastmod,
+        }
+    }
+
+    pub fn from_article(article: &Article) -> Self {
+        let meta = article.meta.as_ref();
+        Entry::new(
+            &article.permalink,
+            meta.and_then(|m| m.updated_at.or(m.created_at)),
+        )
+    }
+}
+
+/// The most recent date of the given articles, for listing pages.
+pub fn latest<'a, I: IntoIterator<Item = &'a ArticleSummary>>(
+    summaries: I,
+) -> Option<DateTime<Utc>> {
+    summaries
+        .into_iter()
+        .filter_map(|s| s.updated_at.or(s.created_at))
+        .max()
+}
+
+pub fn sitemap(base_url: &str, entries: &[Entry]) -> String {
+    let base_url = base_url.trim_end_matches('/');
+    let mut entries: Vec<&Entry> = entries.iter().collect();
+    entries.sort_by(|a, b| a.permalink.cmp(&b.permalink));
+    // Pages sharing a URL overwrite each other, so there's only one to list.
+    entries.dedup_by(|a, b| {
+        let duplicate = a.permalink == b.permalink;
+        if duplicate {
+            eprintln!(
+                "More than one page is written to {}, listing it once in the sitemap",
+                a.permalink
+            );
+        }
+        duplicate
+    });
+
+    let mut xml = "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<urlset xmlns=\"http://www.sitemaps.org/schemas/sitemap/0.9\">\n".to_string();
+    for entry in entries {
+        xml.push_str(&format!(
+            "  <url>\n    <loc>{}{}</loc>\n",
+            base_url,
+            escape(&entry.permalink)
+        ));
+        if let Some(lastmod) = entry.lastmod {
+            xml.push_str(&format!(
+                "    <lastmod>{}</lastmod>\n",
+                lastmod.to_rfc3339()
+            ));
+        }
+        xml.push_str("  </url>\n");
+    }
+    xml.push_str("</urlset>\n");
+    xml
+}
+
+pub fn robots(base_url: &str, opts: &RobotsOptions) -> String {
+    let mut txt = "User-agent: *\n".to_string();
+    if opts.disallow.is_empty() {
+        txt.push_str("Disallow:\n");
+    }
+    for path in &opts.disallow {
+        txt.push_str(&format!("Disallow: {}\n", path));
+    }
+    txt.push_str(&format!(
+        "\nSitemap: {}/sitemap.xml\n",
+        base_url.trim_end_matches('/')
+    ));
+    txt
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Entry, RobotsOptions};
+
+    #[test]
+    fn sitemap_lists_sorted_entries() {
+        let entries = vec![
+            Entry::new("/b", Some("2021-01-01T00:00:00Z".parse().unwrap())),
+            Entry::new("/index", None),
+            Entry::new("/a&b", None),
+            Entry::new("/b", None),
+            Entry::new("/journal/", None),
+        ];
+        let xml = super::sitemap("https://example.com/", &entries);
+
+        assert!(xml.contains("<loc>https://example.com/</loc>\n  </url>\n  <url>\n    <loc>https://example.com/a&amp;b</loc>"));
+        assert!(xml.contains(
+            "<loc>https://example.com/b</loc>\n    <lastmod>2021-01-01T00:00:00+00:00</lastmod>"
+        ));
+        assert_eq!(xml.matches("<loc>https://example.com/b</loc>").count(), 1);
+        assert!(xml.contains("<loc>https://example.com/journal/</loc>"));
+    }
+
+    #[test]
+    fn robots_references_sitemap() {
+        let opts = RobotsOptions {
+            disallow: vec!["/private".to_string()],
+        };
+
+        assert_eq!(
+            super::robots("https://example.com", &opts),
+            "User-agent: *\nDisallow: /private\n\nSitemap: https://example.com/sitemap.xml\n"
+        );
+    }
+}
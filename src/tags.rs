@@ -0,0 +1,327 @@
+/// Tags
+///
+/// Inline `#tags` in the body of an article, as used in Obsidian. Tags are
+/// made up of letters, numbers, `_`, `-` and `/` (for nested tags like
+/// `#status/seedling`), and need at least one character that isn't a number,
+/// so that e.g. `#1` isn't mistaken for one. Tags in code, headings and links
+/// are left alone.
+///
+/// Nested tags roll up into their parents: an article tagged `#lang/rust` is
+/// also listed under `lang`, and `lang` gets a tag page of its own.
+use crate::content::{new_node, Article, Content};
+use comrak::nodes::{AstNode, NodeValue};
+use comrak::Arena;
+use regex::Regex;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+
+lazy_static! {
+    static ref TAG: Regex =
+        Regex::new(r"(?:^|\s)#([\p{L}\p{N}_/-]*[\p{L}_/-][\p{L}\p{N}_/-]*)").unwrap();
+}
+
+/// Replaces every inline tag in the document with a link to its tag page,
+/// returning the tags found in order of appearance.
+pub fn transform<'a>(arena: &'a Arena<AstNode<'a>>, root: &'a AstNode<'a>) -> Vec<String> {
+    let mut tags = vec![];
+    let nodes: Vec<&'a AstNode<'a>> = root.descendants().collect();
+    for node in nodes {
+        let literal = match node.data.borrow().value {
+            NodeValue::Text(ref literal) => String::from_utf8_lossy(literal).to_string(),
+            _ => continue,
+        };
+        if !literal.contains('#') || in_heading_or_link(node) {
+            continue;
+        }
+
+        let mut rest = node;
+        let mut last = 0;
+        for captures in TAG.captures_iter(&literal) {
+            let tag = match captures.get(1) {
+                Some(tag) => tag,
+                None => continue,
+            };
+            let name = tag.as_str().trim_end_matches('/');
+            // Everything from the `#` up to the end of the tag.
+            let start = tag.start() - 1;
+            let end = tag.start() + name.len();
+
+            let html = format!("<a href=\"{}\" class=\"tag\">#{}</a>", url(name), name);
+            rest.data.borrow_mut().value =
+                NodeValue::Text(literal.as_bytes()[last..start].to_vec());
+            let link = new_node(arena, NodeValue::HtmlInline(html.into_bytes()));
+            rest.insert_after(link);
+            rest = new_node(arena, NodeValue::Text(vec![]));
+            link.insert_after(rest);
+            last = end;
+
+            tags.push(name.to_string());
+        }
+        if last > 0 {
+            rest.data.borrow_mut().value = NodeValue::Text(literal.as_bytes()[last..].to_vec());
+        }
+    }
+    tags
+}
+
+fn in_heading_or_link<'a>(node: &'a AstNode<'a>) -> bool {
+    node.ancestors().any(|ancestor| {
+        matches!(
+            ancestor.data.borrow().value,
+            NodeValue::Heading(_) | NodeValue::Link(_) | NodeValue::Image(_)
+        )
+    })
+}
+
+/// Turns a tag into the slug used in its URL. Every segment of a nested tag
+/// is slugified on its own, which also case-folds them, so that `#Rust` and
+/// `#rust` end up on the same page.
+pub fn slugify(tag: &str) -> String {
+    tag.split('/')
+        .map(slug::slugify)
+        .filter(|segment| !segment.is_empty())
+        .collect::<Vec<String>>()
+        .join("/")
+}
+
+pub fn url(tag: &str) -> String {
+    format!("/tags/{}", slugify(tag))
+}
+
+/// A tag as passed to templates.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Tag {
+    pub name: String,
+    pub slug: String,
+    pub url: String,
+}
+
+impl Tag {
+    pub fn new(name: &str) -> Self {
+        Tag {
+            name: name.to_string(),
+            slug: slugify(name),
+            url: url(name),
+        }
+    }
+}
+
+/// Combines tag lists, dropping tags with the same slug while keeping the
+/// order.
+pub fn merge<'a, I: IntoIterator<Item = &'a String>>(tags: I) -> Vec<String> {
+    let mut merged: Vec<String> = vec![];
+    let mut slugs: Vec<String> = vec![];
+    for tag in tags {
+        let slug = slugify(tag);
+        if !slug.is_empty() && !slugs.contains(&slug) {
+            merged.push(tag.clone());
+            slugs.push(slug);
+        }
+    }
+    merged
+}
+
+/// All articles filed under a tag, along with the name it's displayed by.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct TagGroup {
+    pub name: String,
+    pub articles: Vec<String>,
+}
+
+/// Groups tags by slug and rolls nested tags up into their parents, mapping
+/// every tag and all of its ancestors to the ids of the articles tagged with
+/// it or anything below it. A tag spelled in different ways is displayed the
+/// way it's spelled most often. Article ids are sorted, so that the groups
+/// come out the same in every build.
+pub fn roll_up(tags: &HashMap<String, Vec<String>>) -> BTreeMap<String, TagGroup> {
+    let mut rolled: BTreeMap<String, TagGroup> = BTreeMap::new();
+    let mut spellings: HashMap<String, HashMap<String, usize>> = HashMap::new();
+    for (tag, ids) in tags {
+        let segments: Vec<&str> = tag
+            .split('/')
+            .filter(|segment| !slug::slugify(segment).is_empty())
+            .collect();
+        for depth in 1..=segments.len() {
+            let name = segments[..depth].join("/");
+            let slug = slugify(&name);
+            *spellings
+                .entry(slug.clone())
+                .or_default()
+                .entry(name)
+                .or_default() += ids.len();
+            rolled
+                .entry(slug)
+                .or_default()
+                .articles
+                .extend(ids.iter().cloned());
+        }
+    }
+
+    for (slug, group) in rolled.iter_mut() {
+        group.name = spellings[slug]
+            .iter()
+            .max_by(|(a, a_count), (b, b_count)| a_count.cmp(b_count).then(b.cmp(a)))
+            .map(|(name, _)| name.clone())
+            .unwrap_or_default();
+        group.articles.sort();
+        group.articles.dedup();
+    }
+    rolled
+}
+
+/// The notes in the `tags` folder describing a tag, by slug, e.g.
+/// `tags/Rust.md` for `rust`. Notes there not matching any tag aren't
+/// descriptions, but published like any other note.
+pub fn descriptions<'a>(
+    contents: &'a HashMap<String, Content>,
+    rolled: &BTreeMap<String, TagGroup>,
+) -> HashMap<String, &'a Article> {
+    let mut descriptions = HashMap::new();
+    for c in contents.values() {
+        let article = match c {
+            Content::Article(a) => a,
+            _ => continue,
+        };
+        if let Some(name) = article.id.strip_prefix("tags/") {
+            let slug = slugify(name);
+            if rolled.contains_key(&slug) {
+                descriptions.insert(slug, article.as_ref());
+            } else {
+                eprintln!(
+                    "{} doesn't describe any tag, so it's published as a note of its own",
+                    article.src
+                );
+            }
+        }
+    }
+    descriptions
+}
+
+/// A tag in the tag tree, along with the tags nested under it.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct TagNode {
+    /// The full tag, e.g. `lang/rust`.
+    pub tag: String,
+    /// The last segment of the tag, e.g. `rust`.
+    pub name: String,
+    pub slug: String,
+    pub url: String,
+    /// Number of articles tagged with this tag or any tag nested under it.
+    pub count: usize,
+    pub children: Vec<TagNode>,
+}
+
+/// Builds the tag tree from rolled up tags, sorted by slug.
+pub fn tree(rolled: &BTreeMap<String, TagGroup>) -> Vec<TagNode> {
+    children_of(rolled, None)
+}
+
+fn children_of(rolled: &BTreeMap<String, TagGroup>, parent_slug: Option<&str>) -> Vec<TagNode> {
+    rolled
+        .iter()
+        .filter(|(slug, _)| parent(slug) == parent_slug)
+        .map(|(slug, group)| TagNode {
+            tag: group.name.clone(),
+            name: group.name.rsplit('/').next().unwrap_or("").to_string(),
+            slug: slug.clone(),
+            url: format!("/tags/{}", slug),
+            count: group.articles.len(),
+            children: children_of(rolled, Some(slug)),
+        })
+        .collect()
+}
+
+/// Lists every tag in the tree, parents before their children.
+pub fn flatten(tree: &[TagNode]) -> Vec<&TagNode> {
+    tree.iter()
+        .flat_map(|node| std::iter::once(node).chain(flatten(&node.children)))
+        .collect()
+}
+
+/// The slug of the tag a nested tag is nested under, e.g. `lang` for
+/// `lang/rust`.
+pub fn parent(slug: &str) -> Option<&str> {
+    slug.rsplit_once('/').map(|(parent, _)| parent)
+}
+
+/// Looks up a tag anywhere in the tag tree by its slug.
+pub fn find<'a>(tree: &'a [TagNode], slug: &str) -> Option<&'a TagNode> {
+    tree.iter().find_map(|node| {
+        if node.slug == slug {
+            Some(node)
+        } else {
+            find(&node.children, slug)
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::MarkdownOptions;
+    use crate::content::{parse_raw, Article, Content};
+    use std::collections::HashMap;
+
+    #[test]
+    fn transform_links_inline_tags() {
+        let raw = "# Not #a-tag\n\nSee #rust and #status/seedling, not `#code`, #1 or a#b.";
+        let content = parse_raw(raw, &MarkdownOptions::default()).unwrap();
+
+        assert_eq!(content.tags, vec!["rust", "status/seedling"]);
+        assert_eq!(content.title.unwrap(), "<h1>Not #a-tag</h1>\n");
+        assert_eq!(
+            content.ingress.unwrap(),
+            "<p>See <a href=\"/tags/rust\" class=\"tag\">#rust</a> and <a href=\"/tags/status/seedling\" class=\"tag\">#status/seedling</a>, not <code>#code</code>, #1 or a#b.</p>\n"
+        );
+    }
+
+    #[test]
+    fn slugify_folds_case_and_keeps_nesting() {
+        assert_eq!(super::slugify("Lang/Rust Async"), "lang/rust-async");
+        assert_eq!(super::slugify("Café//"), "cafe");
+    }
+
+    #[test]
+    fn roll_up_includes_descendants() {
+        let mut tags = HashMap::new();
+        tags.insert("lang/rust/async".to_string(), vec!["a".to_string()]);
+        tags.insert(
+            "lang/rust".to_string(),
+            vec!["b".to_string(), "a".to_string()],
+        );
+        tags.insert("Lang/Go".to_string(), vec!["c".to_string()]);
+
+        let rolled = super::roll_up(&tags);
+        assert_eq!(rolled["lang"].articles, vec!["a", "b", "c"]);
+        assert_eq!(rolled["lang/rust"].articles.len(), 2);
+
+        let tree = super::tree(&rolled);
+        assert_eq!(tree.len(), 1);
+        let names: Vec<&str> = tree[0].children.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(names, vec!["Go", "rust"]);
+        assert_eq!(super::find(&tree, "lang/rust/async").unwrap().count, 1);
+    }
+
+    #[test]
+    fn descriptions_match_existing_tags() {
+        let mut tags = HashMap::new();
+        tags.insert("Rust".to_string(), vec!["a".to_string()]);
+        let rolled = super::roll_up(&tags);
+
+        let mut contents = HashMap::new();
+        for id in ["tags/Rust", "tags/ideas", "a"] {
+            let article = Article {
+                id: id.to_string(),
+                permalink: format!("/{}", id),
+                src: format!("{}.md", id),
+                meta: None,
+                content: None,
+                raw: String::new(),
+            };
+            contents.insert(id.to_string(), Content::Article(Box::new(article)));
+        }
+
+        let descriptions = super::descriptions(&contents, &rolled);
+        assert_eq!(descriptions.len(), 1);
+        assert_eq!(descriptions["rust"].id, "tags/Rust");
+    }
+}
@@ -0,0 +1,125 @@
+/// Tasks
+///
+/// Task list items (`- [ ]` and `- [x]`) collected from every article, so
+/// that open tasks can be listed across the whole site. Each task remembers
+/// the heading it was listed under.
+use crate::content::{plain_text, Content};
+use comrak::nodes::{AstNode, NodeValue};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Task {
+    pub text: String,
+    pub done: bool,
+    /// The text of the closest heading above the task, if any.
+    pub heading: Option<String>,
+}
+
+/// A task along with the article it's from.
+#[derive(Clone, Debug, Serialize)]
+pub struct SiteTask {
+    #[serde(flatten)]
+    pub task: Task,
+    pub id: String,
+    pub title: String,
+    pub permalink: String,
+}
+
+/// Collects the tasks of a document, in order.
+pub fn extract<'a>(root: &'a AstNode<'a>) -> Vec<Task> {
+    root.descendants()
+        .filter_map(|node| {
+            let done = match node.data.borrow().value {
+                NodeValue::TaskItem(done) => done,
+                _ => return None,
+            };
+            // The checkbox is the first inline of the item's paragraph.
+            let paragraph = node.parent()?;
+            Some(Task {
+                text: plain_text(paragraph),
+                done,
+                heading: heading_above(root, node),
+            })
+        })
+        .collect()
+}
+
+// Headings are top level blocks, so we look for the closest one preceding
+// the top level block the task is nested in.
+fn heading_above<'a>(root: &'a AstNode<'a>, node: &'a AstNode<'a>) -> Option<String> {
+    let block = node
+        .ancestors()
+        .find(|ancestor| ancestor.parent().is_some_and(|p| p.same_node(root)))?;
+    block
+        .preceding_siblings()
+        .find(|sibling| matches!(sibling.data.borrow().value, NodeValue::Heading(_)))
+        .map(plain_text)
+}
+
+/// Gathers the tasks of all articles, grouped by article title.
+pub fn aggregate(contents: &HashMap<String, Content>) -> Vec<SiteTask> {
+    let mut tasks: Vec<SiteTask> = contents
+        .values()
+        .filter_map(|c| match c {
+            Content::Article(a) => Some(a),
+            _ => None,
+        })
+        .flat_map(|article| {
+            let title = article.title();
+            article
+                .content
+                .iter()
+                .flat_map(|content| content.tasks.iter())
+                .map(move |task| SiteTask {
+                    task: task.clone(),
+                    id: article.id.clone(),
+                    title: title.clone(),
+                    permalink: article.permalink.clone(),
+                })
+        })
+        .collect();
+    // Sorting is stable, so tasks keep their order within an article.
+    tasks.sort_by(|a, b| {
+        a.title
+            .to_lowercase()
+            .cmp(&b.title.to_lowercase())
+            .then(a.id.cmp(&b.id))
+    });
+    tasks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Task;
+    use crate::config::MarkdownOptions;
+    use crate::content::parse_raw;
+
+    #[test]
+    fn extract_finds_tasks_and_headings() {
+        let raw =
+            "- [ ] Before\n\n## Chores\n\n- [x] Done *thing*\n- Not a task\n  - [ ] Nested #todo";
+        let content = parse_raw(raw, &MarkdownOptions::default()).unwrap();
+
+        assert_eq!(
+            content.tasks,
+            vec![
+                Task {
+                    text: "Before".to_string(),
+                    done: false,
+                    heading: None,
+                },
+                Task {
+                    text: "Done thing".to_string(),
+                    done: true,
+                    heading: Some("Chores".to_string()),
+                },
+                Task {
+                    text: "Nested #todo".to_string(),
+                    done: false,
+                    heading: Some("Chores".to_string()),
+                },
+            ]
+        );
+    }
+}
@@ -2,11 +2,11 @@ use crate::Content;
 use std::collections::HashMap;
 use std::io::{Read, Write};
 
-pub trait Reader {
+pub trait Reader: Send + Sync {
     fn read_all(&self) -> Result<HashMap<String, Content>, crate::error::Error>;
-    fn get_reader(&self, src: &str) -> Box<dyn Read>;
+    fn get_reader(&self, src: &str) -> std::io::Result<Box<dyn Read>>;
 }
 
-pub trait Writer {
-    fn get_writer(&self, permalink: &str) -> Box<dyn Write>;
+pub trait Writer: Send + Sync {
+    fn get_writer(&self, permalink: &str) -> std::io::Result<Box<dyn Write>>;
 }